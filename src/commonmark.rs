@@ -0,0 +1,842 @@
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::default::Default;
+use std::iter::repeat;
+
+use parser::{Alignment, Block, Document, LinkReference, ListItem, ListType, Spanned};
+use parser::front_matter::FrontMatterFormat;
+use parser::inline::{Inline, InlineText, autolink_destination, flanking, is_punctuation,
+                     normalize_label, plain_text};
+
+
+/// options for rendering CommonMark, which choose between the equivalent ways of writing
+/// an element
+#[derive(Show,Clone)]
+pub struct CommonMarkOptions {
+    /// the marker of bullet list items: '-', '*' or '+'
+    pub bullet: char,
+    /// the delimiter after the number of ordered list items: '.' or ')'
+    pub ordered_delimiter: char,
+    /// the delimiter of emphasis: '*' or '_'. Emphasis with '_' doesn't work inside of words
+    pub emphasis: char,
+    /// the delimiter of strong emphasis: '*' or '_'
+    pub strong: char,
+    /// the character of code fences: '`' or '~'. Info strings with '`' always use '~'
+    pub fence: char,
+    /// the character of horizontal rules: '-', '*' or '_'
+    pub rule: char,
+    /// write links and images whose destination and title match a link reference definition as
+    /// reference links, and keep the definitions. Otherwise all links are inline links
+    pub reference_links: bool,
+    /// number the items of ordered lists consecutively from the number of the first item.
+    /// Otherwise each item keeps its own number
+    pub renumber: bool,
+    /// write headers of level 1 and 2 as setext headers, underlined with "=" and "-"
+    pub setext_headers: bool,
+    /// the width up to which the lines of paragraphs are filled, including the indentation of
+    /// their container blocks. 0 keeps the line breaks of the source
+    pub line_width: uint,
+}
+
+impl Default for CommonMarkOptions {
+    fn default() -> CommonMarkOptions {
+        CommonMarkOptions {
+            bullet: '-',
+            ordered_delimiter: '.',
+            emphasis: '*',
+            strong: '*',
+            fence: '`',
+            rule: '-',
+            reference_links: true,
+            renumber: true,
+            setext_headers: false,
+            line_width: 0,
+        }
+    }
+}
+
+
+/// renders a document as normalized CommonMark, which parses to the same document
+///
+/// Code blocks are fenced, and headers are ATX headers unless setext headers are chosen. Front matter, footnote definitions and
+/// link reference definitions are kept.
+pub fn render_commonmark(document: &Document, options: &CommonMarkOptions) -> String {
+    let mut renderer = CommonMarkRenderer {
+        options: options,
+        references: document.link_references.as_slice(),
+        at_line_start: false,
+        in_table: false,
+        in_link_text: false,
+        wrapping: false,
+        indent: 0,
+        follows_paragraph: false,
+        context: String::new(),
+        text_offsets: Vec::new(),
+        next_text: 0,
+    };
+    let mut parts: Vec<String> = Vec::new();
+
+    if let Some(ref front_matter) = document.front_matter {
+        let delimiter = match front_matter.format {
+            FrontMatterFormat::YAML => "---",
+            FrontMatterFormat::TOML => "+++",
+        };
+        parts.push(format!("{}\n{}{}", delimiter, front_matter.raw, delimiter));
+    }
+    if !document.blocks.is_empty() {
+        parts.push(renderer.render_blocks(document.blocks.as_slice(), false));
+    }
+    for footnote in document.footnotes.iter().chain(document.unreferenced_footnotes.iter()) {
+        renderer.indent = 4;
+        let content = renderer.render_blocks(footnote.blocks.as_slice(), false);
+        renderer.indent = 0;
+        parts.push(indent_lines(content.as_slice(), format!("[^{}]: ", footnote.label).as_slice(), "    ", ""));
+    }
+    if options.reference_links && !document.link_references.is_empty() {
+        let definitions: Vec<String> = document.link_references.iter().map(|reference| {
+            let mut definition = format!("[{}]: ", reference.label);
+            push_link_destination(&mut definition, reference.destination.as_slice());
+            push_link_title(&mut definition, reference.title.as_slice());
+            definition
+        }).collect();
+        parts.push(definitions.connect("\n"));
+    }
+
+    let mut out = parts.connect("\n\n");
+    out.push('\n');
+    return out;
+}
+
+
+struct CommonMarkRenderer<'a> {
+    options: &'a CommonMarkOptions,
+    references: &'a [LinkReference],
+    /// whether the next text starts a line, where it mustn't look like the start of a block
+    at_line_start: bool,
+    /// whether "|" has to be escaped, as it would end a table cell
+    in_table: bool,
+    /// whether brackets have to be escaped, as they would end the text of a link or image
+    in_link_text: bool,
+    /// whether spaces and soft breaks are written as "\0", where paragraph lines may be broken
+    wrapping: bool,
+    /// the width of the prefixes of the container blocks
+    indent: uint,
+    /// whether the block directly follows a paragraph line, which it could continue
+    follows_paragraph: bool,
+    /// the texts of the inline content being rendered with the markup of the other inlines, to
+    /// find what the special characters of a text could form markup with
+    context: String,
+    /// where the texts of the inline content start in `context`
+    text_offsets: Vec<uint>,
+    /// the index of the next text to render in `text_offsets`
+    next_text: uint,
+}
+
+impl<'a> CommonMarkRenderer<'a> {
+    /// the blocks separated by blank lines, or by line breaks in tight lists
+    fn render_blocks(&mut self, blocks: &[Spanned<Block>], tight: bool) -> String {
+        let mut out = String::new();
+        let mut previous: Option<&Block> = None;
+        for block in blocks.iter() {
+            if let Some(previous) = previous {
+                out.push_str(if tight { "\n" } else { "\n\n" });
+                // two lists of the same kind would become one list
+                match (previous, &block.node) {
+                    (&Block::List(_, ref items), &Block::List(_, ref next_items))
+                            if is_ordered(items.as_slice()) == is_ordered(next_items.as_slice()) => {
+                        out.push_str("<!-- -->\n\n");
+                    },
+                    _ => {}
+                }
+            }
+            self.follows_paragraph = match previous {
+                Some(&Block::Paragraph(_)) => tight,
+                _ => false,
+            };
+            out.push_str(self.render_block(&block.node).as_slice());
+            self.follows_paragraph = false;
+            previous = Some(&block.node);
+        }
+        return out;
+    }
+
+    fn render_block(&mut self, block: &Block) -> String {
+        return match *block {
+            Block::Rule => {
+                // "---" after a paragraph line would be a setext underline
+                let rule = if self.follows_paragraph && self.options.rule == '-' { '*' } else { self.options.rule };
+                repeat(rule).take(3).collect()
+            },
+            Block::Header(level, ref text, _) if self.options.setext_headers && level <= 2 && !text.is_empty() &&
+                                                 !self.follows_paragraph => {
+                let content = self.render_inlines_at_line_start(text);
+                let underline_char = if level == 1 { '=' } else { '-' };
+                let underline: String = repeat(underline_char).take(cmp::max(3, content.chars().count())).collect();
+                format!("{}\n{}", content, underline)
+            },
+            Block::Header(level, ref text, _) => {
+                let mut out: String = repeat('#').take(level).collect();
+                if !text.is_empty() {
+                    out.push(' ');
+                    let content = self.render_inlines_at_line_start(text);
+                    // a closing sequence of "#" after a space would be removed. A "#" at the
+                    // start is already escaped
+                    let run_start = content.as_slice().trim_right_matches('#').len();
+                    if run_start < content.len() && content.as_slice().slice_to(run_start).ends_with(" ") {
+                        out.push_str(content.as_slice().slice_to(run_start));
+                        out.push('\\');
+                        out.push_str(content.as_slice().slice_from(run_start));
+                    } else {
+                        out.push_str(content.as_slice());
+                    }
+                }
+                out
+            },
+            Block::Paragraph(ref text) if self.options.line_width > 0 => {
+                self.wrapping = true;
+                let content = self.render_inlines_at_line_start(text);
+                self.wrapping = false;
+                let width = if self.options.line_width > self.indent { self.options.line_width - self.indent } else { 1 };
+                wrap_words(content.as_slice(), width)
+            },
+            Block::Paragraph(ref text) => {
+                self.render_inlines_at_line_start(text)
+            },
+            Block::Code(ref info, ref code) => {
+                let info = match *info {
+                    Some(ref info) => plain_text(info),
+                    None => "".to_string(),
+                };
+                let fence_char = if self.options.fence == '`' && info.as_slice().contains_char('`') {
+                    '~'
+                } else {
+                    self.options.fence
+                };
+                let fence_length = cmp::max(3, longest_run(code.as_slice(), fence_char) + 1);
+                let fence: String = repeat(fence_char).take(fence_length).collect();
+                format!("{}{}\n{}{}", fence, info, code, fence)
+            },
+            Block::BlockQuote(ref blocks) => {
+                self.indent += 2;
+                let content = self.render_blocks(blocks.as_slice(), false);
+                self.indent -= 2;
+                indent_lines(content.as_slice(), "> ", "> ", ">")
+            },
+            Block::HTMLBlock(ref html) => {
+                html.as_slice().trim_right_matches('\n').to_string()
+            },
+            Block::List(tight, ref items) => {
+                self.render_list(tight, items.as_slice())
+            },
+            Block::Table(ref alignments, ref header, ref rows) => {
+                self.render_table(alignments.as_slice(), header.as_slice(), rows.as_slice())
+            },
+        }
+    }
+
+    fn render_list(&mut self, tight: bool, items: &[ListItem]) -> String {
+        // renumbered items are numbered from the number of the first item
+        let start = match items.first() {
+            Some(&ListItem { listtype: ListType::Ordered(start, _), .. }) => start,
+            _ => 1,
+        };
+        let mut rendered_items = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            let marker = match item.listtype {
+                ListType::Ordered(number, _) => {
+                    let number = if self.options.renumber { start + i } else { number };
+                    format!("{}{}", number, self.options.ordered_delimiter)
+                },
+                ListType::Unordered(_) => self.options.bullet.to_string(),
+            };
+            let mut content = match item.checked {
+                Some(true) => "[x] ".to_string(),
+                Some(false) => "[ ] ".to_string(),
+                None => "".to_string(),
+            };
+            self.indent += marker.len() + 1;
+            content.push_str(self.render_blocks(item.blocks.as_slice(), tight).as_slice());
+            self.indent -= marker.len() + 1;
+            let indent: String = repeat(' ').take(marker.len() + 1).collect();
+            let rendered = if content.trim_right().is_empty() {
+                marker
+            } else {
+                indent_lines(content.as_slice(), format!("{} ", marker).as_slice(), indent.as_slice(), "")
+            };
+            rendered_items.push(rendered);
+        }
+        return rendered_items.connect(if tight { "\n" } else { "\n\n" });
+    }
+
+    fn render_table(&mut self, alignments: &[Alignment], header: &[InlineText], rows: &[Vec<InlineText>]) -> String {
+        self.in_table = true;
+        let mut lines = vec![self.render_table_row(header)];
+        let delimiters: Vec<&str> = alignments.iter().map(|alignment| match *alignment {
+            Alignment::None => "---",
+            Alignment::Left => ":---",
+            Alignment::Center => ":---:",
+            Alignment::Right => "---:",
+        }).collect();
+        lines.push(format!("| {} |", delimiters.connect(" | ")));
+        for row in rows.iter() {
+            lines.push(self.render_table_row(row.as_slice()));
+        }
+        self.in_table = false;
+        return lines.connect("\n");
+    }
+
+    fn render_table_row(&mut self, cells: &[InlineText]) -> String {
+        let cells: Vec<String> = cells.iter().map(|cell| self.render_inlines(cell)).collect();
+        return format!("| {} |", cells.connect(" | "));
+    }
+
+
+    /// inline content that starts a line, like that of paragraphs and headers
+    fn render_inlines_at_line_start(&mut self, text: &InlineText) -> String {
+        self.at_line_start = true;
+        return self.render_inlines(text);
+    }
+
+    fn render_inlines(&mut self, text: &InlineText) -> String {
+        self.context = String::new();
+        self.text_offsets = Vec::new();
+        self.next_text = 0;
+        self.push_context(text);
+
+        let mut out = String::new();
+        for inline in text.iter() {
+            self.render_inline(&mut out, &inline.node);
+        }
+        return out;
+    }
+
+    fn render_inline(&mut self, out: &mut String, inline: &Inline) {
+        match *inline {
+            Inline::Text(ref text) => {
+                self.push_escaped_text(out, text.as_slice());
+            },
+            Inline::SoftBreak if self.wrapping => {
+                // the lines are filled again
+                out.push('\0');
+            },
+            Inline::SoftBreak => {
+                out.push('\n');
+                self.at_line_start = true;
+                return;
+            },
+            Inline::HardBreak => {
+                out.push_str("\\\n");
+                self.at_line_start = true;
+                return;
+            },
+            Inline::URIAutolink(ref uri) => {
+                // `www.` links without a scheme are only recognized without angle brackets
+                if autolink_destination(uri.as_slice()) == *uri {
+                    out.push_str(format!("<{}>", uri).as_slice());
+                } else {
+                    out.push_str(uri.as_slice());
+                }
+            },
+            Inline::EmailAutolink(ref address) => {
+                out.push_str(format!("<{}>", address).as_slice());
+            },
+            Inline::HTMLTag(ref html) => {
+                self.keep_on_line(out);
+                out.push_str(html.as_slice());
+            },
+            Inline::CodeSpan(ref code) => {
+                let fence: String = repeat('`').take(longest_run(code.as_slice(), '`') + 1).collect();
+                // spaces keep backticks at the ends apart from the fence, and one space on each
+                // side would be removed
+                let code = code.as_slice();
+                let padded = code.starts_with("`") || code.ends_with("`") ||
+                    (code.starts_with(" ") && code.ends_with(" ") && !code.chars().all(|c| c == ' '));
+                let padding = if padded { " " } else { "" };
+                out.push_str(format!("{}{}{}{}{}", fence, padding, code, padding, fence).as_slice());
+            },
+            Inline::Link(ref content, ref destination, ref title) => {
+                self.render_link(out, content, destination.as_slice(), title.as_slice(), false);
+            },
+            Inline::Image(ref content, ref source, ref title) => {
+                self.render_link(out, content, source.as_slice(), title.as_slice(), true);
+            },
+            Inline::Emph(ref content) => {
+                let delimiter = self.options.emphasis.to_string();
+                self.render_delimited(out, content, delimiter.as_slice());
+            },
+            Inline::Strong(ref content) => {
+                let delimiter: String = repeat(self.options.strong).take(2).collect();
+                self.render_delimited(out, content, delimiter.as_slice());
+            },
+            Inline::Strikethrough(ref content) => {
+                self.render_delimited(out, content, "~~");
+            },
+            Inline::FootnoteReference(ref label, _) => {
+                self.keep_on_line(out);
+                out.push_str(format!("[^{}]", label).as_slice());
+            },
+        }
+        self.at_line_start = false;
+    }
+
+    /// adds the texts of inlines to the context, and the delimiters and brackets of the other
+    /// inlines in place of their content
+    fn push_context(&mut self, text: &InlineText) {
+        for inline in text.iter() {
+            match inline.node {
+                Inline::Text(ref text) => {
+                    let offset = self.context.len();
+                    self.text_offsets.push(offset);
+                    self.context.push_str(text.as_slice());
+                },
+                Inline::SoftBreak | Inline::HardBreak => self.context.push('\n'),
+                Inline::CodeSpan(ref code) => {
+                    let fence: String = repeat('`').take(longest_run(code.as_slice(), '`') + 1).collect();
+                    self.context.push_str(format!("{}code{}", fence, fence).as_slice());
+                },
+                Inline::URIAutolink(_) | Inline::EmailAutolink(_) | Inline::HTMLTag(_) => {
+                    self.context.push_str("<html>");
+                },
+                Inline::Link(ref content, _, _) | Inline::Image(ref content, _, _) => {
+                    if let Inline::Image(..) = inline.node {
+                        self.context.push('!');
+                    }
+                    self.context.push('[');
+                    self.push_context(content);
+                    self.context.push_str("](");
+                },
+                Inline::Emph(ref content) => {
+                    let delimiter = self.options.emphasis.to_string();
+                    self.push_delimited_context(content, delimiter.as_slice());
+                },
+                Inline::Strong(ref content) => {
+                    let delimiter: String = repeat(self.options.strong).take(2).collect();
+                    self.push_delimited_context(content, delimiter.as_slice());
+                },
+                Inline::Strikethrough(ref content) => {
+                    self.push_delimited_context(content, "~~");
+                },
+                Inline::FootnoteReference(..) => self.context.push_str("[^]"),
+            }
+        }
+    }
+
+    fn push_delimited_context(&mut self, content: &InlineText, delimiter: &str) {
+        self.context.push_str(delimiter);
+        self.push_context(content);
+        self.context.push_str(delimiter);
+    }
+
+    /// prevents a line break before the next node when filling lines, for inline HTML and
+    /// footnote references that could start an HTML block or footnote definition at the start of
+    /// a line
+    fn keep_on_line(&self, out: &mut String) {
+        if self.wrapping && out.as_slice().ends_with("\0") {
+            out.pop();
+            out.push(' ');
+        }
+    }
+
+    fn render_delimited(&mut self, out: &mut String, content: &InlineText, delimiter: &str) {
+        out.push_str(delimiter);
+        self.at_line_start = false;
+        let count = content.len();
+        for (i, inline) in content.iter().enumerate() {
+            match inline.node {
+                // emphasis right inside of delimiters of the same character would merge with them
+                Inline::Emph(ref inner) if (i == 0 || i + 1 == count) &&
+                                           delimiter.char_at(0) == self.options.emphasis => {
+                    let other = if self.options.emphasis == '*' { "_" } else { "*" };
+                    self.render_delimited(out, inner, other);
+                },
+                _ => self.render_inline(out, &inline.node),
+            }
+        }
+        out.push_str(delimiter);
+    }
+
+    /// a link or image, as a reference link if there is a matching definition
+    fn render_link(&mut self, out: &mut String, content: &InlineText, destination: &str, title: &str,
+                   image: bool) {
+        if image {
+            out.push('!');
+        }
+        out.push('[');
+        self.at_line_start = false;
+        let in_link_text = self.in_link_text;
+        self.in_link_text = true;
+        for inline in content.iter() {
+            self.render_inline(out, &inline.node);
+        }
+        self.in_link_text = in_link_text;
+        out.push(']');
+
+        let text = plain_text(content);
+        match self.find_reference(destination, title, text.as_slice()) {
+            Some(reference) => {
+                // a collapsed reference can't be taken as the start of another link
+                if normalize_label(reference.label.as_slice()) == normalize_label(text.as_slice()) {
+                    out.push_str("[]");
+                } else {
+                    out.push_str(format!("[{}]", reference.label).as_slice());
+                }
+            },
+            None => {
+                out.push('(');
+                push_link_destination(out, destination);
+                push_link_title(out, title);
+                out.push(')');
+            }
+        }
+    }
+
+    /// the link reference definition with the destination and title of a link, preferring the
+    /// one whose label is the link text
+    fn find_reference(&self, destination: &str, title: &str, text: &str) -> Option<&'a LinkReference> {
+        if !self.options.reference_links {
+            return None;
+        }
+        let references = self.references;
+        let matching = references.iter().filter(|reference| {
+            reference.destination.as_slice() == destination && reference.title.as_slice() == title
+        });
+        let normalized_text = normalize_label(text);
+        let mut first = None;
+        for reference in matching {
+            if normalize_label(reference.label.as_slice()) == normalized_text {
+                return Some(reference);
+            }
+            if first.is_none() {
+                first = Some(reference);
+            }
+        }
+        return first;
+    }
+
+    /// text with backslashes before the characters that would otherwise be markup
+    fn push_escaped_text(&mut self, out: &mut String, text: &str) {
+        let offset = self.text_offsets[self.next_text];
+        self.next_text += 1;
+        let mut start = 0;
+        if self.at_line_start {
+            start = push_escaped_line_start(out, text);
+        }
+        // whether the rest of the current run of "*", "_", "~" or "`" is escaped
+        let mut run_escaped = false;
+        let mut run_end = 0;
+        for (i, ch) in text.slice_from(start).char_indices() {
+            let i = start + i;
+            // the text from the character on, followed by the markup after the text
+            let rest = self.context.slice_from(offset + i);
+            let next = rest.chars().nth(1).unwrap_or(' ');
+            let escape = match ch {
+                '*' | '_' | '~' | '`' => {
+                    if i >= run_end {
+                        let length = text.slice_from(i).chars().take_while(|&c| c == ch).count();
+                        run_end = i + length;
+                        run_escaped = could_be_delimiter(out.as_slice(), rest, ch, length);
+                    }
+                    run_escaped
+                },
+                '\\' => next.is_whitespace() || (next.is_ascii() && is_punctuation(next)),
+                '<' => (next.is_ascii() && next.is_alphabetic()) || next == '/' || next == '!' || next == '?',
+                '!' => next == '[',
+                '[' => self.in_link_text,
+                ']' => self.in_link_text || self.could_close_link(out.as_slice(), next),
+                '|' => self.in_table,
+                '&' => looks_like_entity(text.slice_from(i)),
+                ' ' if self.wrapping => {
+                    out.push('\0');
+                    continue;
+                },
+                _ => false,
+            };
+            if escape {
+                out.push('\\');
+            }
+            out.push(ch);
+        }
+    }
+
+    /// whether a "]" could end a link, a reference or a footnote reference with the text after
+    /// the last "[" that is still open, followed by `next`
+    fn could_close_link(&self, out: &str, next: char) -> bool {
+        let opener = match open_bracket(out) {
+            Some(opener) => opener,
+            None => return false,
+        };
+        let label = out.slice_from(opener + 1).replace("\0", " ");
+        let defined = self.references.iter().any(|reference| {
+            normalize_label(reference.label.as_slice()) == normalize_label(label.as_slice())
+        });
+        return next == '(' || next == '[' || defined || label.as_slice().starts_with("^") ||
+            (next == ':' && out.slice_to(opener).trim().is_empty());
+    }
+}
+
+
+/// the last character of the output, where a "\0" is a space. The start of the output is like
+/// the start of a line
+fn last_char(out: &str) -> char {
+    return match out.chars().next_back() {
+        Some('\0') | None => ' ',
+        Some(ch) => ch,
+    }
+}
+
+/// whether a run of `length` times `ch` at the start of `rest` could open or close emphasis,
+/// strikethrough or a code span after the output
+///
+/// Strikethrough and code spans need a matching run later on, so an opening run without one
+/// stays unescaped.
+fn could_be_delimiter(out: &str, rest: &str, ch: char, length: uint) -> bool {
+    let before = last_char(out);
+    let after_run = rest.slice_from(length);
+    let after = after_run.chars().next().unwrap_or(' ');
+    // the run would be longer with the markup around it, unless that is escaped
+    let escaped_before = out.ends_with(format!("\\{}", ch).as_slice());
+    if (before == ch && !escaped_before) || after == ch {
+        return true;
+    }
+    let (left_flanking, right_flanking) = flanking(before, after);
+    return match ch {
+        '_' => (left_flanking && (!right_flanking || is_punctuation(before))) ||
+               (right_flanking && (!left_flanking || is_punctuation(after))),
+        '~' => length <= 2 && left_flanking && has_run(after_run, ch, length, true),
+        '`' => has_run(after_run, ch, length, false),
+        _ => left_flanking || right_flanking,
+    }
+}
+
+/// whether a text contains a run of exactly `length` times `ch`, which is right-flanking if
+/// `closing` is given
+fn has_run(text: &str, ch: char, length: uint, closing: bool) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != ch {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < chars.len() && chars[end] == ch {
+            end += 1;
+        }
+        if end - i == length {
+            let before = if i > 0 { chars[i - 1] } else { ch };
+            let after = if end < chars.len() { chars[end] } else { ' ' };
+            if !closing || flanking(before, after).1 {
+                return true;
+            }
+        }
+        i = end;
+    }
+    return false;
+}
+
+/// the byte index of the last "[" of the output without a "]" after it, unless it is escaped
+fn open_bracket(out: &str) -> Option<uint> {
+    let bytes = out.as_bytes();
+    let mut i = bytes.len();
+    while i > 0 {
+        i -= 1;
+        let escaped = i > 0 && bytes[i - 1] == b'\\';
+        match bytes[i] {
+            b']' if !escaped => return None,
+            b'[' if !escaped => return Some(i),
+            _ => {}
+        }
+    }
+    return None;
+}
+
+
+/// escapes what would start a block at the start of a line: a header, block quote, list item,
+/// setext underline, horizontal rule or code fence. Returns the number of bytes pushed
+fn push_escaped_line_start(out: &mut String, text: &str) -> uint {
+    match text.chars().next() {
+        Some('#') | Some('>') | Some('-') | Some('+') | Some('=') => {
+            out.push('\\');
+            return 0;
+        },
+        Some(c) if c == '*' || c == '_' || c == '`' || c == '~' => {
+            // the escaping of delimiter runs doesn't look for list markers, rules and fences
+            out.push('\\');
+            out.push(c);
+            return 1;
+        },
+        Some(c) if c.is_digit(10) => {
+            // an ordered list marker
+            let digits = text.chars().take_while(|c| c.is_digit(10)).count();
+            if text.slice_from(digits).starts_with(".") || text.slice_from(digits).starts_with(")") {
+                out.push_str(text.slice_to(digits));
+                out.push('\\');
+                return digits;
+            }
+            return 0;
+        },
+        _ => return 0,
+    }
+}
+
+/// fills lines with the words separated by "\0" up to a width, as far as the words allow
+///
+/// Only single spaces are line break opportunities, as trailing spaces could make a hard line
+/// break. Words that start a line are escaped like the start of a paragraph.
+fn wrap_words(text: &str, width: uint) -> String {
+    let mut out = String::new();
+    let mut line_length = 0u;
+    let mut previous_empty = false;
+    for (i, word) in text.split('\0').enumerate() {
+        let first_line_length = word.split('\n').next().unwrap().chars().count();
+        let mut start = 0;
+        if i > 0 {
+            if line_length > 0 && !previous_empty && !word.is_empty() &&
+                    line_length + 1 + first_line_length > width {
+                out.push('\n');
+                start = push_escaped_line_start(&mut out, word);
+            } else {
+                out.push(' ');
+            }
+        }
+        out.push_str(word.slice_from(start));
+        // the line may also have ended with a hard line break in the word
+        let line_start = out.as_slice().rfind('\n').map(|index| index + 1).unwrap_or(0);
+        line_length = out.as_slice().slice_from(line_start).chars().count();
+        previous_empty = word.is_empty();
+    }
+    return out;
+}
+
+/// whether a text starts with something that would be parsed as an entity or numeric character
+/// reference
+fn looks_like_entity(text: &str) -> bool {
+    let name_length = text.slice_from(1).chars()
+        .take_while(|&c| c.is_alphanumeric() || c == '#').count();
+    return name_length > 0 && text.slice_from(1 + name_length).starts_with(";");
+}
+
+
+/// prefixes the first line of a text with `first`, the other lines with `rest`, and blank lines
+/// with `blank`
+fn indent_lines(text: &str, first: &str, rest: &str, blank: &str) -> String {
+    let mut out = String::new();
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        if i == 0 {
+            out.push_str(first);
+        } else if line.is_empty() {
+            out.push_str(blank);
+        } else {
+            out.push_str(rest);
+        }
+        out.push_str(line);
+    }
+    return out;
+}
+
+/// the length of the longest run of a character
+fn longest_run(text: &str, ch: char) -> uint {
+    let mut longest = 0;
+    let mut current = 0;
+    for c in text.chars() {
+        current = if c == ch { current + 1 } else { 0 };
+        longest = cmp::max(longest, current);
+    }
+    return longest;
+}
+
+fn is_ordered(items: &[ListItem]) -> bool {
+    return match items.first() {
+        Some(&ListItem { listtype: ListType::Ordered(..), .. }) => true,
+        _ => false,
+    }
+}
+
+/// a link destination, in angle brackets if it is empty or contains spaces or unbalanced
+/// parentheses
+fn push_link_destination(out: &mut String, destination: &str) {
+    let opening = destination.chars().filter(|&c| c == '(').count();
+    let closing = destination.chars().filter(|&c| c == ')').count();
+    if destination.is_empty() || opening != closing ||
+            destination.chars().any(|c| c == ' ' || c == '<' || c == '>' || c.is_control()) {
+        out.push('<');
+        for ch in destination.chars() {
+            if ch == '<' || ch == '>' || ch == '\\' {
+                out.push('\\');
+            }
+            out.push(ch);
+        }
+        out.push('>');
+    } else {
+        out.push_str(destination.replace("\\", "\\\\").as_slice());
+    }
+}
+
+/// a link title in double quotes after a space, if there is one
+fn push_link_title(out: &mut String, title: &str) {
+    if title.is_empty() {
+        return;
+    }
+    out.push_str(" \"");
+    for ch in title.chars() {
+        if ch == '"' || ch == '\\' {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out.push('"');
+}
+
+
+#[test]
+fn test_commonmark() {
+    use parser::{ParseOptions, parse_markdown};
+
+    let markdown = "Title\n=====\n\n* _a_ __b__ `` ` `` [x][ref] [y](</my url> 'z')\n* 1\\. \\# done\n\n\n    code\n\n> quote\ncontinued\n\n[REF]: /url";
+    let document = parse_markdown(markdown, &ParseOptions::gfm());
+    let commonmark = render_commonmark(&document, &Default::default());
+    assert_eq!(commonmark.as_slice(), "# Title\n\n- *a* **b** `` ` `` [x][REF] [y](</my url> \"z\")\n- 1\\. # done\n\n```\ncode\n```\n\n> quote\n> continued\n\n[REF]: /url\n");
+
+    // the output parses to the same document
+    let document = parse_markdown(commonmark.as_slice(), &ParseOptions::gfm());
+    assert_eq!(render_commonmark(&document, &Default::default()), commonmark);
+
+    let markdown = "_*x*_ **_y_**\n\n- foo\n  ***\n- bar\n\n# \\###";
+    let document = parse_markdown(markdown, &ParseOptions::gfm());
+    let commonmark = render_commonmark(&document, &Default::default());
+    assert_eq!(commonmark.as_slice(), "*_x_* **_y_**\n\n- foo\n  ***\n- bar\n\n# \\###\n");
+    let document = parse_markdown(commonmark.as_slice(), &ParseOptions::gfm());
+    assert_eq!(render_commonmark(&document, &Default::default()), commonmark);
+
+    // special characters are only escaped where they could be markup
+    let markdown = "snake_case, a < b, ~/dir, array[0], C:\\dir and 2 * 3\n";
+    let document = parse_markdown(markdown, &ParseOptions::gfm());
+    assert_eq!(render_commonmark(&document, &Default::default()).as_slice(), markdown);
+
+    let markdown = "\\*a\\* \\_b\\_ \\<span> \\~~c~~ \\`d` [e\\](f) [^g\\]\n";
+    let document = parse_markdown(markdown, &ParseOptions::gfm());
+    assert_eq!(render_commonmark(&document, &Default::default()).as_slice(), markdown);
+}
+
+#[test]
+fn test_commonmark_style() {
+    use parser::{ParseOptions, parse_markdown};
+
+    let options = CommonMarkOptions {
+        bullet: '*', fence: '~', renumber: false, setext_headers: true, line_width: 20, ..Default::default()
+    };
+    let markdown = "# Title\n\n### Section\n\n3. one\n7. two\n\n> aaaa bbbb cccc ddd - eee fff  \nand 12. runs\n\n- a\n\n```rust\nx\n```";
+    let document = parse_markdown(markdown, &ParseOptions::gfm());
+    let commonmark = render_commonmark(&document, &options);
+    assert_eq!(commonmark.as_slice(), "Title\n=====\n\n### Section\n\n3. one\n7. two\n\n> aaaa bbbb cccc ddd\n> \\- eee fff\\\n> and 12. runs\n\n* a\n\n~~~rust\nx\n~~~\n");
+
+    let document = parse_markdown(commonmark.as_slice(), &ParseOptions::gfm());
+    assert_eq!(render_commonmark(&document, &options), commonmark);
+
+    // inline HTML isn't moved to the start of a line, where it would start an HTML block
+    let document = parse_markdown("aaaa bbbb cccc dddd <span>x</span>", &ParseOptions::gfm());
+    assert_eq!(render_commonmark(&document, &options).as_slice(), "aaaa bbbb cccc\ndddd <span>x</span>\n");
+}
@@ -0,0 +1,574 @@
+use std::ascii::AsciiExt;
+use std::default::Default;
+
+use parser::{Alignment, Document, Block, Footnote, ListItem, ListType, Spanned};
+use parser::inline::{Inline, InlineText, autolink_destination, plain_text};
+use toc::{TocEntry, is_toc_marker, table_of_contents};
+
+
+/// the tags that the tagfilter of GitHub Flavored Markdown disables in raw HTML
+static FILTERED_TAGS: [&'static str; 9] = [
+    "title", "textarea", "style", "xmp", "iframe", "noembed", "noframes", "script", "plaintext"
+];
+
+/// the URL schemes that are allowed in safe mode by default
+static DEFAULT_URL_SCHEMES: [&'static str; 6] = ["http", "https", "ftp", "mailto", "irc", "ircs"];
+
+
+/// options for rendering HTML
+///
+/// The default options render the HTML of the CommonMark spec.
+#[derive(Show,Clone)]
+pub struct RenderOptions {
+    /// pass raw HTML blocks and tags through. Otherwise they are replaced by an HTML comment
+    pub raw_html: bool,
+    /// leave out raw HTML, and the URLs of links, images and autolinks whose scheme isn't in
+    /// `url_schemes`, for rendering untrusted input
+    pub safe: bool,
+    /// show raw HTML that isn't passed through as escaped text instead of leaving it out
+    pub escape_raw_html: bool,
+    /// disable the tags of GitHub Flavored Markdown's tagfilter, like `<script>` and `<iframe>`,
+    /// in raw HTML that is passed through, by escaping their "<"
+    pub tagfilter: bool,
+    /// the lowercase URL schemes allowed in safe mode. Relative URLs are always allowed, and
+    /// images may also use data URLs of PNG, GIF, JPEG and WebP images
+    pub url_schemes: Vec<String>,
+    /// replace a top level paragraph consisting of "[TOC]" by the table of contents
+    pub toc: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            raw_html: true,
+            safe: false,
+            escape_raw_html: false,
+            tagfilter: false,
+            url_schemes: DEFAULT_URL_SCHEMES.iter().map(|s| s.to_string()).collect(),
+            toc: false,
+        }
+    }
+}
+
+
+/// renders a document to HTML, following the output conventions of the CommonMark spec
+pub fn render_html(document: &Document, options: &RenderOptions) -> String {
+    let mut renderer = HtmlRenderer {
+        out: String::new(),
+        options: options,
+        footnote_references: Vec::new(),
+    };
+    let toc = if options.toc { table_of_contents(document) } else { Vec::new() };
+    for block in document.blocks.iter() {
+        if options.toc && is_toc_marker(&block.node) {
+            renderer.cr();
+            push_toc(&mut renderer.out, toc.as_slice());
+        } else {
+            renderer.render_block(&block.node, false);
+        }
+    }
+    if !document.footnotes.is_empty() {
+        renderer.render_footnotes(document.footnotes.as_slice());
+    }
+    return renderer.out;
+}
+
+
+/// renders a table of contents as nested lists of links to the headers
+pub fn render_toc(entries: &[TocEntry]) -> String {
+    let mut out = String::new();
+    push_toc(&mut out, entries);
+    return out;
+}
+
+fn push_toc(out: &mut String, entries: &[TocEntry]) {
+    if entries.is_empty() {
+        return;
+    }
+    out.push_str("<ul>\n");
+    for entry in entries.iter() {
+        out.push_str("<li>");
+        match entry.id {
+            Some(ref id) => {
+                out.push_str("<a href=\"#");
+                escape_href(out, id.as_slice());
+                out.push_str("\">");
+                escape_html(out, entry.text.as_slice());
+                out.push_str("</a>");
+            },
+            None => escape_html(out, entry.text.as_slice()),
+        }
+        if !entry.children.is_empty() {
+            out.push('\n');
+            push_toc(out, entry.children.as_slice());
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str("</ul>\n");
+}
+
+
+struct HtmlRenderer<'a> {
+    out: String,
+    options: &'a RenderOptions,
+    /// the number of rendered references to each footnote, by footnote number - 1
+    footnote_references: Vec<uint>,
+}
+
+impl<'a> HtmlRenderer<'a> {
+    /// starts a new line, unless the output is already at the beginning of one
+    fn cr(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with("\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn render_blocks(&mut self, blocks: &[Spanned<Block>], tight: bool) {
+        for block in blocks.iter() {
+            self.render_block(&block.node, tight);
+        }
+    }
+
+    fn render_block(&mut self, block: &Block, tight: bool) {
+        match *block {
+            Block::Rule => {
+                self.cr();
+                self.out.push_str("<hr />\n");
+            },
+            Block::Header(level, ref text, ref id) => {
+                self.cr();
+                self.out.push_str(format!("<h{}", level).as_slice());
+                if let Some(ref id) = *id {
+                    self.out.push_str(" id=\"");
+                    escape_html(&mut self.out, id.as_slice());
+                    self.out.push('"');
+                }
+                self.out.push('>');
+                self.render_inlines(text);
+                self.out.push_str(format!("</h{}>\n", level).as_slice());
+            },
+            Block::Paragraph(ref text) => {
+                // paragraphs in tight lists are not wrapped in <p> tags
+                if tight {
+                    self.render_inlines(text);
+                } else {
+                    self.cr();
+                    self.out.push_str("<p>");
+                    self.render_inlines(text);
+                    self.out.push_str("</p>\n");
+                }
+            },
+            Block::Code(ref info, ref code) => {
+                self.cr();
+                self.out.push_str("<pre><code");
+                if let Some(ref info) = *info {
+                    // only the first word of the info string is used as language
+                    let info_string = plain_text(info);
+                    let language = info_string.as_slice().split(' ').next().unwrap_or("");
+                    if !language.is_empty() {
+                        self.out.push_str(" class=\"language-");
+                        escape_html(&mut self.out, language);
+                        self.out.push('"');
+                    }
+                }
+                self.out.push('>');
+                escape_html(&mut self.out, code.as_slice());
+                self.out.push_str("</code></pre>\n");
+            },
+            Block::BlockQuote(ref blocks) => {
+                self.cr();
+                self.out.push_str("<blockquote>\n");
+                self.render_blocks(blocks.as_slice(), false);
+                self.cr();
+                self.out.push_str("</blockquote>\n");
+            },
+            Block::HTMLBlock(ref html) => {
+                self.cr();
+                self.render_raw_html(html.as_slice());
+                self.cr();
+            },
+            Block::List(is_tight, ref items) => {
+                self.render_list(is_tight, items.as_slice());
+            },
+            Block::Table(ref alignments, ref header, ref rows) => {
+                self.render_table(alignments.as_slice(), header.as_slice(), rows.as_slice());
+            },
+        }
+    }
+
+    fn render_list(&mut self, tight: bool, items: &[ListItem]) {
+        self.cr();
+        let ordered = match items.first() {
+            Some(item) => {
+                match item.listtype {
+                    ListType::Ordered(1, _) => {
+                        self.out.push_str("<ol>\n");
+                        true
+                    },
+                    ListType::Ordered(start, _) => {
+                        self.out.push_str(format!("<ol start=\"{}\">\n", start).as_slice());
+                        true
+                    },
+                    ListType::Unordered(_) => {
+                        self.out.push_str("<ul>\n");
+                        false
+                    }
+                }
+            },
+            None => {
+                self.out.push_str("<ul>\n");
+                false
+            }
+        };
+
+        for item in items.iter() {
+            self.out.push_str("<li>");
+            let checkbox = match item.checked {
+                Some(true) => "<input type=\"checkbox\" checked=\"\" disabled=\"\" /> ",
+                Some(false) => "<input type=\"checkbox\" disabled=\"\" /> ",
+                None => "",
+            };
+            match item.blocks.first() {
+                // in loose lists, the checkbox is part of the first paragraph
+                Some(&Spanned { node: Block::Paragraph(ref text), .. }) if !tight && !checkbox.is_empty() => {
+                    self.cr();
+                    self.out.push_str("<p>");
+                    self.out.push_str(checkbox);
+                    self.render_inlines(text);
+                    self.out.push_str("</p>\n");
+                    self.render_blocks(item.blocks.slice_from(1), tight);
+                },
+                _ => {
+                    self.out.push_str(checkbox);
+                    self.render_blocks(item.blocks.as_slice(), tight);
+                },
+            }
+            self.out.push_str("</li>\n");
+        }
+
+        self.out.push_str(if ordered { "</ol>\n" } else { "</ul>\n" });
+    }
+
+    fn render_table(&mut self, alignments: &[Alignment], header: &[InlineText], rows: &[Vec<InlineText>]) {
+        self.cr();
+        self.out.push_str("<table>\n<thead>\n");
+        self.render_table_row(alignments, header, "th");
+        self.out.push_str("</thead>\n");
+        if !rows.is_empty() {
+            self.out.push_str("<tbody>\n");
+            for row in rows.iter() {
+                self.render_table_row(alignments, row.as_slice(), "td");
+            }
+            self.out.push_str("</tbody>\n");
+        }
+        self.out.push_str("</table>\n");
+    }
+
+    fn render_table_row(&mut self, alignments: &[Alignment], cells: &[InlineText], tag: &str) {
+        self.out.push_str("<tr>\n");
+        for (cell, alignment) in cells.iter().zip(alignments.iter()) {
+            self.out.push('<');
+            self.out.push_str(tag);
+            match *alignment {
+                Alignment::None => {},
+                Alignment::Left => self.out.push_str(" align=\"left\""),
+                Alignment::Center => self.out.push_str(" align=\"center\""),
+                Alignment::Right => self.out.push_str(" align=\"right\""),
+            }
+            self.out.push('>');
+            self.render_inlines(cell);
+            self.out.push_str(format!("</{}>\n", tag).as_slice());
+        }
+        self.out.push_str("</tr>\n");
+    }
+
+    /// the footnotes section at the end of the document, with links back to the references
+    fn render_footnotes(&mut self, footnotes: &[Footnote]) {
+        self.cr();
+        self.out.push_str("<section class=\"footnotes\">\n<ol>\n");
+        for footnote in footnotes.iter() {
+            self.out.push_str(format!("<li id=\"fn-{}\">\n", footnote.number).as_slice());
+            // the back references go at the end of the last paragraph, or after the last block
+            let count = footnote.blocks.len();
+            match footnote.blocks.last() {
+                Some(&Spanned { node: Block::Paragraph(ref text), .. }) => {
+                    self.render_blocks(footnote.blocks.slice_to(count - 1), false);
+                    self.cr();
+                    self.out.push_str("<p>");
+                    self.render_inlines(text);
+                    self.out.push(' ');
+                    self.render_footnote_backrefs(footnote.number);
+                    self.out.push_str("</p>\n");
+                },
+                _ => {
+                    self.render_blocks(footnote.blocks.as_slice(), false);
+                    self.cr();
+                    self.render_footnote_backrefs(footnote.number);
+                    self.out.push('\n');
+                }
+            }
+            self.out.push_str("</li>\n");
+        }
+        self.out.push_str("</ol>\n</section>\n");
+    }
+
+    /// a link back to each reference of a footnote
+    fn render_footnote_backrefs(&mut self, number: uint) {
+        let references = self.footnote_references.get(number - 1).map_or(1, |&count| count);
+        for i in range(1, references + 1) {
+            if i > 1 {
+                self.out.push(' ');
+            }
+            self.out.push_str(format!("<a href=\"#{}\" class=\"footnote-backref\">\u{21A9}",
+                                      footnote_reference_id(number, i)).as_slice());
+            if i > 1 {
+                self.out.push_str(format!("<sup>{}</sup>", i).as_slice());
+            }
+            self.out.push_str("</a>");
+        }
+    }
+
+    /// raw HTML, unless it is disabled
+    fn render_raw_html(&mut self, html: &str) {
+        if self.options.raw_html && !self.options.safe {
+            if self.options.tagfilter {
+                push_filtered_html(&mut self.out, html);
+            } else {
+                self.out.push_str(html);
+            }
+        } else if self.options.escape_raw_html {
+            escape_html(&mut self.out, html);
+        } else {
+            self.out.push_str("<!-- raw HTML omitted -->");
+        }
+    }
+
+    /// an URL for use in href and src attributes, which is left out in safe mode unless its
+    /// scheme is allowed
+    fn render_url(&mut self, url: &str, image: bool) {
+        if !self.options.safe || is_allowed_url(url, image, self.options.url_schemes.as_slice()) {
+            escape_href(&mut self.out, url);
+        }
+    }
+
+
+    fn render_inlines(&mut self, text: &InlineText) {
+        for inline in text.iter() {
+            self.render_inline(&inline.node);
+        }
+    }
+
+    fn render_inline(&mut self, inline: &Inline) {
+        match *inline {
+            Inline::URIAutolink(ref uri) => {
+                self.out.push_str("<a href=\"");
+                self.render_url(autolink_destination(uri.as_slice()).as_slice(), false);
+                self.out.push_str("\">");
+                escape_html(&mut self.out, uri.as_slice());
+                self.out.push_str("</a>");
+            },
+            Inline::EmailAutolink(ref address) => {
+                self.out.push_str("<a href=\"");
+                self.render_url(format!("mailto:{}", address).as_slice(), false);
+                self.out.push_str("\">");
+                escape_html(&mut self.out, address.as_slice());
+                self.out.push_str("</a>");
+            },
+            Inline::HTMLTag(ref html) => {
+                self.render_raw_html(html.as_slice());
+            },
+            Inline::CodeSpan(ref code) => {
+                self.out.push_str("<code>");
+                escape_html(&mut self.out, code.as_slice());
+                self.out.push_str("</code>");
+            },
+            Inline::Link(ref text, ref destination, ref title) => {
+                self.out.push_str("<a href=\"");
+                self.render_url(destination.as_slice(), false);
+                self.out.push('"');
+                if !title.is_empty() {
+                    self.out.push_str(" title=\"");
+                    escape_html(&mut self.out, title.as_slice());
+                    self.out.push('"');
+                }
+                self.out.push('>');
+                self.render_inlines(text);
+                self.out.push_str("</a>");
+            },
+            Inline::Image(ref text, ref source, ref title) => {
+                self.out.push_str("<img src=\"");
+                self.render_url(source.as_slice(), true);
+                self.out.push_str("\" alt=\"");
+                escape_html(&mut self.out, plain_text(text).as_slice());
+                self.out.push('"');
+                if !title.is_empty() {
+                    self.out.push_str(" title=\"");
+                    escape_html(&mut self.out, title.as_slice());
+                    self.out.push('"');
+                }
+                self.out.push_str(" />");
+            },
+            Inline::Emph(ref content) => {
+                self.out.push_str("<em>");
+                self.render_inlines(content);
+                self.out.push_str("</em>");
+            },
+            Inline::Strong(ref content) => {
+                self.out.push_str("<strong>");
+                self.render_inlines(content);
+                self.out.push_str("</strong>");
+            },
+            Inline::Strikethrough(ref content) => {
+                self.out.push_str("<del>");
+                self.render_inlines(content);
+                self.out.push_str("</del>");
+            },
+            Inline::FootnoteReference(_, number) => {
+                while self.footnote_references.len() < number {
+                    self.footnote_references.push(0);
+                }
+                self.footnote_references[number - 1] += 1;
+                let id = footnote_reference_id(number, self.footnote_references[number - 1]);
+                self.out.push_str(format!("<sup class=\"footnote-ref\"><a href=\"#fn-{}\" id=\"{}\">{}</a></sup>",
+                                          number, id, number).as_slice());
+            },
+            Inline::Text(ref text) => {
+                escape_html(&mut self.out, text.as_slice());
+            },
+            Inline::HardBreak => {
+                self.out.push_str("<br />\n");
+            },
+            Inline::SoftBreak => {
+                self.out.push('\n');
+            },
+        }
+    }
+}
+
+
+/// the id of the n-th reference to a footnote, which the back reference links to
+fn footnote_reference_id(number: uint, n: uint) -> String {
+    return if n == 1 {
+        format!("fnref-{}", number)
+    } else {
+        format!("fnref-{}-{}", number, n)
+    }
+}
+
+
+/// whether an URL is relative or uses one of the allowed schemes
+///
+/// Browsers ignore whitespace and control characters in schemes, so they are ignored here as well.
+/// Images may also use data URLs of common image formats.
+fn is_allowed_url(url: &str, image: bool, schemes: &[String]) -> bool {
+    let url: String = url.chars().filter(|&c| c > ' ' && c != '\x7F').collect::<String>().to_ascii_lowercase();
+    let url = url.as_slice();
+    let scheme = match url.find(':') {
+        Some(end) if is_url_scheme(url.slice_to(end)) => url.slice_to(end),
+        _ => return true,
+    };
+    if image && scheme == "data" {
+        return url.starts_with("data:image/png") || url.starts_with("data:image/gif") ||
+               url.starts_with("data:image/jpeg") || url.starts_with("data:image/webp");
+    }
+    return schemes.iter().any(|allowed| allowed.as_slice() == scheme);
+}
+
+/// whether a string is a syntactically valid URL scheme. Otherwise the colon belongs to the path
+/// of a relative URL
+fn is_url_scheme(s: &str) -> bool {
+    return match s.chars().next() {
+        Some(first) => first.is_alphabetic() && first.is_ascii() &&
+                       s.chars().all(|c| c.is_ascii() && (c.is_alphanumeric() || "+.-".contains_char(c))),
+        None => false,
+    }
+}
+
+
+/// raw HTML with the "<" of the tags of the tagfilter escaped, so browsers don't interpret them
+fn push_filtered_html(out: &mut String, html: &str) {
+    let mut pos = 0;
+    while pos < html.len() {
+        let ch = html.char_at(pos);
+        if ch == '<' && is_filtered_tag(html.slice_from(pos + 1)) {
+            out.push_str("&lt;");
+        } else {
+            out.push(ch);
+        }
+        pos += ch.len_utf8();
+    }
+}
+
+/// whether a tag name, which may start with "/" for a closing tag, is one of the filtered tags
+fn is_filtered_tag(s: &str) -> bool {
+    let s = if s.starts_with("/") { s.slice_from(1) } else { s };
+    return FILTERED_TAGS.iter().any(|tag| {
+        s.len() >= tag.len() && s.is_char_boundary(tag.len()) && s.slice_to(tag.len()).eq_ignore_ascii_case(*tag) &&
+            match s.slice_from(tag.len()).chars().next() {
+                None => true,
+                Some(c) => c.is_whitespace() || c == '/' || c == '>',
+            }
+    });
+}
+
+
+pub fn escape_html(out: &mut String, s: &str) {
+    for ch in s.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(ch),
+        }
+    }
+}
+
+
+/// escapes an URL for use in href and src attributes
+///
+/// Characters that are not allowed in URLs are percent-encoded, existing percent-encodings are
+/// left alone.
+pub fn escape_href(out: &mut String, url: &str) {
+    for &byte in url.as_bytes().iter() {
+        let ch = byte as char;
+        if byte < 128 && (ch.is_alphanumeric() || "-_.+!*(),%#@?=;:/$~".contains_char(ch)) {
+            out.push(ch);
+        } else if ch == '&' {
+            out.push_str("&amp;");
+        } else if ch == '\'' {
+            out.push_str("&#x27;");
+        } else {
+            out.push_str(format!("%{:02X}", byte).as_slice());
+        }
+    }
+}
+
+
+#[test]
+fn test_task_list_items() {
+    use parser::{ParseOptions, parse_markdown};
+
+    let document = parse_markdown("- [x] done\n- [ ] todo", &ParseOptions::gfm());
+    assert_eq!(render_html(&document, &Default::default()).as_slice(),
+               "<ul>\n<li><input type=\"checkbox\" checked=\"\" disabled=\"\" /> done</li>\n<li><input type=\"checkbox\" disabled=\"\" /> todo</li>\n</ul>\n");
+
+    let document = parse_markdown("- [x] done\n\n- [ ] todo", &ParseOptions::gfm());
+    assert_eq!(render_html(&document, &Default::default()).as_slice(),
+               "<ul>\n<li>\n<p><input type=\"checkbox\" checked=\"\" disabled=\"\" /> done</p>\n</li>\n<li>\n<p><input type=\"checkbox\" disabled=\"\" /> todo</p>\n</li>\n</ul>\n");
+}
+
+#[test]
+fn test_safe_mode() {
+    use parser::parse_markdown;
+
+    let document = parse_markdown("<script>x</script>\n\n[a](JavaScript:alert(1)) <javascript:x> [b](c:d) [e](/f:g) <SCRIPT src=x> ![i](data:image/png;base64,x)",
+                                  &Default::default());
+
+    let html = render_html(&document, &RenderOptions { tagfilter: true, ..Default::default() });
+    assert_eq!(html.as_slice(), "&lt;script>x&lt;/script>\n<p><a href=\"JavaScript:alert(1)\">a</a> <a href=\"javascript:x\">javascript:x</a> <a href=\"c:d\">b</a> <a href=\"/f:g\">e</a> &lt;SCRIPT src=x> <img src=\"data:image/png;base64,x\" alt=\"i\" /></p>\n");
+
+    let options = RenderOptions { safe: true, escape_raw_html: true, url_schemes: vec!["c".to_string()], ..Default::default() };
+    let html = render_html(&document, &options);
+    assert_eq!(html.as_slice(), "&lt;script&gt;x&lt;/script&gt;\n<p><a href=\"\">a</a> <a href=\"\">javascript:x</a> <a href=\"c:d\">b</a> <a href=\"/f:g\">e</a> &lt;SCRIPT src=x&gt; <img src=\"data:image/png;base64,x\" alt=\"i\" /></p>\n");
+}
@@ -0,0 +1,338 @@
+use parser::{Alignment, Block, Document, Footnote, ListItem, ListType, Position, Span, Spanned};
+use parser::front_matter::FrontMatterFormat;
+use parser::inline::{Inline, InlineText, autolink_destination, plain_text};
+
+
+/// the version of the JSON schema, which changes when a node or field is removed or changes its
+/// meaning. New fields may be added without a new version
+pub static JSON_VERSION: uint = 1;
+
+
+/// a JSON value, with the fields of objects in the order they are written
+enum Json {
+    Null,
+    Bool(bool),
+    Number(uint),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn string(s: &str) -> Json {
+        return Json::Str(s.to_string());
+    }
+
+    fn write(&self, out: &mut String) {
+        match *self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+            Json::Number(n) => out.push_str(n.to_string().as_slice()),
+            Json::Str(ref s) => push_json_string(out, s.as_slice()),
+            Json::Array(ref values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    value.write(out);
+                }
+                out.push(']');
+            },
+            Json::Object(ref fields) => {
+                out.push('{');
+                for (i, &(name, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    push_json_string(out, name);
+                    out.push(':');
+                    value.write(out);
+                }
+                out.push('}');
+            },
+        }
+    }
+}
+
+/// a string in double quotes, with the characters escaped that JSON doesn't allow in strings
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+
+/// renders a document as JSON, in the schema of `JSON_VERSION` described in the README
+///
+/// Every node is an object with its "type" and "span", and the other fields of its kind.
+pub fn render_json(document: &Document) -> String {
+    let front_matter = match document.front_matter {
+        Some(ref front_matter) => {
+            let format = match front_matter.format {
+                FrontMatterFormat::YAML => "yaml",
+                FrontMatterFormat::TOML => "toml",
+            };
+            let values = front_matter.values.iter().map(|&(ref key, ref value)| {
+                Json::Array(vec![Json::string(key.as_slice()), Json::string(value.as_slice())])
+            }).collect();
+            Json::Object(vec![
+                ("format", Json::string(format)),
+                ("raw", Json::string(front_matter.raw.as_slice())),
+                ("values", Json::Array(values)),
+                ("span", span_json(&front_matter.span)),
+            ])
+        },
+        None => Json::Null,
+    };
+    let footnotes = document.footnotes.iter()
+        .map(|footnote| footnote_json(footnote, Json::Number(footnote.number))).collect();
+    let unreferenced_footnotes = document.unreferenced_footnotes.iter()
+        .map(|footnote| footnote_json(footnote, Json::Null)).collect();
+    let link_references = document.link_references.iter().map(|reference| Json::Object(vec![
+        ("type", Json::string("link_reference_definition")),
+        ("span", span_json(&reference.span)),
+        ("label", Json::string(reference.label.as_slice())),
+        ("destination", Json::string(reference.destination.as_slice())),
+        ("title", Json::string(reference.title.as_slice())),
+    ])).collect();
+
+    let json = Json::Object(vec![
+        ("version", Json::Number(JSON_VERSION)),
+        ("type", Json::string("document")),
+        ("front_matter", front_matter),
+        ("children", blocks_json(document.blocks.as_slice())),
+        ("footnotes", Json::Array(footnotes)),
+        ("unreferenced_footnotes", Json::Array(unreferenced_footnotes)),
+        ("link_references", Json::Array(link_references)),
+    ]);
+    let mut out = String::new();
+    json.write(&mut out);
+    out.push('\n');
+    return out;
+}
+
+
+fn position_json(position: &Position) -> Json {
+    return Json::Object(vec![
+        ("line", Json::Number(position.line)),
+        ("column", Json::Number(position.column)),
+        ("offset", Json::Number(position.offset)),
+    ]);
+}
+
+fn span_json(span: &Span) -> Json {
+    return Json::Object(vec![("start", position_json(&span.start)), ("end", position_json(&span.end))]);
+}
+
+/// a node with its type, span and other fields
+fn node_json(node_type: &str, span: &Span, fields: Vec<(&'static str, Json)>) -> Json {
+    let mut all_fields = vec![("type", Json::string(node_type)), ("span", span_json(span))];
+    all_fields.extend(fields.into_iter());
+    return Json::Object(all_fields);
+}
+
+
+fn blocks_json(blocks: &[Spanned<Block>]) -> Json {
+    return Json::Array(blocks.iter().map(|block| block_json(block)).collect());
+}
+
+fn block_json(block: &Spanned<Block>) -> Json {
+    let span = &block.span;
+    return match block.node {
+        Block::Rule => {
+            node_json("rule", span, vec![])
+        },
+        Block::Header(level, ref text, ref id) => {
+            let id = match *id {
+                Some(ref id) => Json::string(id.as_slice()),
+                None => Json::Null,
+            };
+            node_json("header", span, vec![
+                ("level", Json::Number(level)),
+                ("id", id),
+                ("children", inlines_json(text)),
+            ])
+        },
+        Block::Paragraph(ref text) => {
+            node_json("paragraph", span, vec![("children", inlines_json(text))])
+        },
+        Block::Code(ref info, ref code) => {
+            let info = match *info {
+                Some(ref info) => Json::Str(plain_text(info)),
+                None => Json::Null,
+            };
+            node_json("code_block", span, vec![("info", info), ("literal", Json::string(code.as_slice()))])
+        },
+        Block::BlockQuote(ref blocks) => {
+            node_json("block_quote", span, vec![("children", blocks_json(blocks.as_slice()))])
+        },
+        Block::HTMLBlock(ref html) => {
+            node_json("html_block", span, vec![("literal", Json::string(html.as_slice()))])
+        },
+        Block::List(tight, ref items) => {
+            let mut fields = vec![("tight", Json::Bool(tight))];
+            match items.first().map(|item| &item.listtype) {
+                Some(&ListType::Ordered(start, delimiter)) => {
+                    fields.push(("ordered", Json::Bool(true)));
+                    fields.push(("start", Json::Number(start)));
+                    fields.push(("delimiter", Json::Str(delimiter.to_string())));
+                },
+                Some(&ListType::Unordered(bullet)) => {
+                    fields.push(("ordered", Json::Bool(false)));
+                    fields.push(("bullet", Json::Str(bullet.to_string())));
+                },
+                None => {}
+            }
+            fields.push(("children", Json::Array(items.iter().map(|item| item_json(item)).collect())));
+            node_json("list", span, fields)
+        },
+        Block::Table(ref alignments, ref header, ref rows) => {
+            let alignments = alignments.iter().map(|alignment| Json::string(match *alignment {
+                Alignment::None => "none",
+                Alignment::Left => "left",
+                Alignment::Center => "center",
+                Alignment::Right => "right",
+            })).collect();
+            let rows = rows.iter().map(|row| row_json(row.as_slice())).collect();
+            node_json("table", span, vec![
+                ("alignments", Json::Array(alignments)),
+                ("header", row_json(header.as_slice())),
+                ("rows", Json::Array(rows)),
+            ])
+        },
+    }
+}
+
+fn item_json(item: &ListItem) -> Json {
+    let mut fields = Vec::new();
+    if let ListType::Ordered(number, _) = item.listtype {
+        fields.push(("number", Json::Number(number)));
+    }
+    let checked = match item.checked {
+        Some(checked) => Json::Bool(checked),
+        None => Json::Null,
+    };
+    fields.push(("checked", checked));
+    fields.push(("children", blocks_json(item.blocks.as_slice())));
+    return node_json("item", &item.span, fields);
+}
+
+/// a footnote definition with its number, which is null if the footnote isn't referenced
+fn footnote_json(footnote: &Footnote, number: Json) -> Json {
+    return node_json("footnote_definition", &footnote.span, vec![
+        ("label", Json::string(footnote.label.as_slice())),
+        ("number", number),
+        ("children", blocks_json(footnote.blocks.as_slice())),
+    ]);
+}
+
+/// the cells of a table row, each as an array of inlines
+fn row_json(cells: &[InlineText]) -> Json {
+    return Json::Array(cells.iter().map(|cell| inlines_json(cell)).collect());
+}
+
+
+fn inlines_json(text: &InlineText) -> Json {
+    return Json::Array(text.iter().map(|inline| inline_json(inline)).collect());
+}
+
+fn inline_json(inline: &Spanned<Inline>) -> Json {
+    let span = &inline.span;
+    return match inline.node {
+        Inline::Text(ref text) => {
+            node_json("text", span, vec![("literal", Json::string(text.as_slice()))])
+        },
+        Inline::SoftBreak => {
+            node_json("soft_break", span, vec![])
+        },
+        Inline::HardBreak => {
+            node_json("hard_break", span, vec![])
+        },
+        Inline::CodeSpan(ref code) => {
+            node_json("code", span, vec![("literal", Json::string(code.as_slice()))])
+        },
+        Inline::HTMLTag(ref html) => {
+            node_json("html_inline", span, vec![("literal", Json::string(html.as_slice()))])
+        },
+        Inline::URIAutolink(ref uri) => {
+            node_json("autolink", span, vec![
+                ("literal", Json::string(uri.as_slice())),
+                ("destination", Json::Str(autolink_destination(uri.as_slice()))),
+            ])
+        },
+        Inline::EmailAutolink(ref address) => {
+            node_json("autolink", span, vec![
+                ("literal", Json::string(address.as_slice())),
+                ("destination", Json::Str(format!("mailto:{}", address))),
+            ])
+        },
+        Inline::Link(ref content, ref destination, ref title) => {
+            node_json("link", span, vec![
+                ("destination", Json::string(destination.as_slice())),
+                ("title", Json::string(title.as_slice())),
+                ("children", inlines_json(content)),
+            ])
+        },
+        Inline::Image(ref content, ref source, ref title) => {
+            node_json("image", span, vec![
+                ("destination", Json::string(source.as_slice())),
+                ("title", Json::string(title.as_slice())),
+                ("children", inlines_json(content)),
+            ])
+        },
+        Inline::Emph(ref content) => {
+            node_json("emph", span, vec![("children", inlines_json(content))])
+        },
+        Inline::Strong(ref content) => {
+            node_json("strong", span, vec![("children", inlines_json(content))])
+        },
+        Inline::Strikethrough(ref content) => {
+            node_json("strikethrough", span, vec![("children", inlines_json(content))])
+        },
+        Inline::FootnoteReference(ref label, number) => {
+            node_json("footnote_reference", span, vec![
+                ("label", Json::string(label.as_slice())),
+                ("number", Json::Number(number)),
+            ])
+        },
+    }
+}
+
+
+#[test]
+fn test_json() {
+    use std::default::Default;
+    use parser::parse_markdown;
+
+    let document = parse_markdown("# Hi \"you\"\n\n3) *a*\n4) b\n\n```rust\nx\n```", &Default::default());
+    assert_eq!(render_json(&document).as_slice(), concat!(
+        r#"{"version":1,"type":"document","front_matter":null,"children":["#,
+        r#"{"type":"header","span":{"start":{"line":1,"column":1,"offset":0},"end":{"line":1,"column":11,"offset":10}},"level":1,"id":null,"children":["#,
+        r#"{"type":"text","span":{"start":{"line":1,"column":3,"offset":2},"end":{"line":1,"column":11,"offset":10}},"literal":"Hi \"you\""}]},"#,
+        r#"{"type":"list","span":{"start":{"line":3,"column":1,"offset":12},"end":{"line":4,"column":5,"offset":23}},"tight":true,"ordered":true,"start":3,"delimiter":")","children":["#,
+        r#"{"type":"item","span":{"start":{"line":3,"column":1,"offset":12},"end":{"line":3,"column":7,"offset":18}},"number":3,"checked":null,"children":["#,
+        r#"{"type":"paragraph","span":{"start":{"line":3,"column":4,"offset":15},"end":{"line":3,"column":7,"offset":18}},"children":["#,
+        r#"{"type":"emph","span":{"start":{"line":3,"column":4,"offset":15},"end":{"line":3,"column":7,"offset":18}},"children":["#,
+        r#"{"type":"text","span":{"start":{"line":3,"column":5,"offset":16},"end":{"line":3,"column":6,"offset":17}},"literal":"a"}]}]}]},"#,
+        r#"{"type":"item","span":{"start":{"line":4,"column":1,"offset":19},"end":{"line":4,"column":5,"offset":23}},"number":4,"checked":null,"children":["#,
+        r#"{"type":"paragraph","span":{"start":{"line":4,"column":4,"offset":22},"end":{"line":4,"column":5,"offset":23}},"children":["#,
+        r#"{"type":"text","span":{"start":{"line":4,"column":4,"offset":22},"end":{"line":4,"column":5,"offset":23}},"literal":"b"}]}]}]},"#,
+        r#"{"type":"code_block","span":{"start":{"line":6,"column":1,"offset":25},"end":{"line":8,"column":4,"offset":38}},"info":"rust","literal":"x\n"}],"#,
+        r#""footnotes":[],"unreferenced_footnotes":[],"link_references":[]}"#, "\n"));
+
+    // a fence without info string is distinguished from indented code
+    let json = render_json(&parse_markdown("```\nx\n```\n\n    y", &Default::default()));
+    assert!(json.as_slice().contains(r#""info":"","literal":"x\n""#));
+    assert!(json.as_slice().contains(r#""info":null,"literal":"y\n""#));
+}
@@ -0,0 +1,88 @@
+//! A Markdown parser following the CommonMark spec (http://commonmark.org/)
+//!
+//! ```
+//! let document = rustmark::parse("Hello *world*!");
+//! let html = rustmark::render_html(&document);
+//! ```
+//!
+//! Parsing and rendering can be configured with `ParseOptions` and `RenderOptions`, whose
+//! defaults follow the CommonMark spec:
+//!
+//! ```
+//! let options = rustmark::ParseOptions { smart_punctuation: true, ..std::default::Default::default() };
+//! let document = rustmark::parse_with_options("\"Hello\" -- world...", &options);
+//! ```
+//!
+//! `Parser` is a pull parser, which produces a stream of `Event`s instead of a document tree:
+//!
+//! ```
+//! for event in rustmark::Parser::new("Hello *world*!") {
+//!     println!("{:?}", event);
+//! }
+//! ```
+
+#![feature(phase)]
+#[phase(plugin)]
+extern crate regex_macros;
+extern crate regex;
+
+pub use parser::{Alignment, Document, Block, Footnote, LinkReference, ListItem, ListType, ParseOptions,
+                 Position, Span, Spanned};
+pub use parser::inline::{Inline, InlineText};
+pub use parser::front_matter::{FrontMatter, FrontMatterFormat};
+pub use parser::events::{Parser, Event, Tag};
+pub use html::RenderOptions;
+pub use commonmark::CommonMarkOptions;
+pub use toc::{TocEntry, table_of_contents, is_toc_marker, slugify};
+pub use lint::{Diagnostic, LintOptions, LintRule, lint};
+pub use json::JSON_VERSION;
+
+use std::default::Default;
+
+mod parser;
+mod html;
+mod commonmark;
+mod toc;
+mod lint;
+mod json;
+
+
+/// parses a Markdown text into a document tree
+pub fn parse(text: &str) -> Document {
+    return parser::parse_markdown(text, &Default::default());
+}
+
+/// parses a Markdown text into a document tree, with the given options
+pub fn parse_with_options(text: &str, options: &ParseOptions) -> Document {
+    return parser::parse_markdown(text, options);
+}
+
+/// renders a document tree to HTML
+pub fn render_html(document: &Document) -> String {
+    return html::render_html(document, &Default::default());
+}
+
+/// renders a document tree to HTML, with the given options
+pub fn render_html_with_options(document: &Document, options: &RenderOptions) -> String {
+    return html::render_html(document, options);
+}
+
+/// renders a document tree as normalized CommonMark
+pub fn render_commonmark(document: &Document) -> String {
+    return commonmark::render_commonmark(document, &Default::default());
+}
+
+/// renders a document tree as normalized CommonMark, with the given options
+pub fn render_commonmark_with_options(document: &Document, options: &CommonMarkOptions) -> String {
+    return commonmark::render_commonmark(document, options);
+}
+
+/// renders a document tree as JSON, in the versioned schema described in the README
+pub fn render_json(document: &Document) -> String {
+    return json::render_json(document);
+}
+
+/// renders a table of contents to HTML, as nested lists of links to the headers
+pub fn render_toc_html(entries: &[TocEntry]) -> String {
+    return html::render_toc(entries);
+}
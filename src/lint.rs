@@ -0,0 +1,304 @@
+use std::default::Default;
+
+use parser::{Block, Document, ListType, ParseOptions, Position, Spanned, parse_markdown};
+use parser::inline::{Inline, InlineText, plain_text};
+
+
+/// a check of the linter
+#[derive(Show,Clone,Copy,PartialEq)]
+pub enum LintRule {
+    /// a header more than one level below the previous header
+    HeaderIncrement,
+    /// more than one header of level 1
+    MultipleTopLevelHeaders,
+    /// spaces or tabs at the end of a line, except the two spaces of a hard line break
+    TrailingWhitespace,
+    /// a bullet list with another marker than the first bullet list of the document
+    ListMarkerStyle,
+    /// a reference link whose label isn't defined, which is left as text
+    UndefinedReference,
+    /// a link reference definition that no link uses
+    UnusedDefinition,
+    /// a fenced code block without a language in its info string
+    FenceLanguage,
+    /// a URL or email address without angle brackets, which is only a link with the autolinks
+    /// extension
+    BareUrl,
+    /// a tab character, which looks different depending on the editor
+    HardTab,
+}
+
+impl LintRule {
+    /// all rules, in the order of the documentation
+    pub fn all() -> Vec<LintRule> {
+        return vec![
+            LintRule::HeaderIncrement,
+            LintRule::MultipleTopLevelHeaders,
+            LintRule::TrailingWhitespace,
+            LintRule::ListMarkerStyle,
+            LintRule::UndefinedReference,
+            LintRule::UnusedDefinition,
+            LintRule::FenceLanguage,
+            LintRule::BareUrl,
+            LintRule::HardTab,
+        ];
+    }
+
+    /// the name of the rule in configurations and diagnostics
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            LintRule::HeaderIncrement => "header-increment",
+            LintRule::MultipleTopLevelHeaders => "multiple-top-level-headers",
+            LintRule::TrailingWhitespace => "trailing-whitespace",
+            LintRule::ListMarkerStyle => "list-marker-style",
+            LintRule::UndefinedReference => "undefined-reference",
+            LintRule::UnusedDefinition => "unused-definition",
+            LintRule::FenceLanguage => "fence-language",
+            LintRule::BareUrl => "bare-url",
+            LintRule::HardTab => "hard-tab",
+        }
+    }
+
+    /// the rule with a name
+    pub fn from_name(name: &str) -> Option<LintRule> {
+        return LintRule::all().into_iter().find(|rule| rule.name() == name);
+    }
+}
+
+/// a problem found by the linter
+#[derive(Show,Clone)]
+pub struct Diagnostic {
+    pub rule: LintRule,
+    /// where the problem is in the source
+    pub position: Position,
+    pub message: String,
+}
+
+/// options of the linter
+#[derive(Show,Clone)]
+pub struct LintOptions {
+    /// the rules to check, which are all rules by default
+    pub rules: Vec<LintRule>,
+    /// the options to parse documents with, which are the GitHub Flavored Markdown extensions
+    /// and front matter by default
+    pub parse_options: ParseOptions,
+}
+
+impl Default for LintOptions {
+    fn default() -> LintOptions {
+        LintOptions {
+            rules: LintRule::all(),
+            parse_options: ParseOptions { front_matter: true, ..ParseOptions::gfm() },
+        }
+    }
+}
+
+impl LintOptions {
+    pub fn is_enabled(&self, rule: LintRule) -> bool {
+        return self.rules.contains(&rule);
+    }
+
+    pub fn enable(&mut self, rule: LintRule) {
+        if !self.is_enabled(rule) {
+            self.rules.push(rule);
+        }
+    }
+
+    pub fn disable(&mut self, rule: LintRule) {
+        self.rules.retain(|&r| r != rule);
+    }
+}
+
+
+/// checks a Markdown text with the enabled rules, and returns the problems in the order of
+/// the source
+pub fn lint(text: &str, options: &LintOptions) -> Vec<Diagnostic> {
+    let document = parse_markdown(text, &options.parse_options);
+    let mut linter = Linter {
+        text: text,
+        options: options,
+        diagnostics: Vec::new(),
+        header_level: 0,
+        top_level_headers: 0,
+        bullet: None,
+    };
+    linter.check_lines();
+    linter.check_document(&document);
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by(|a, b| a.position.offset.cmp(&b.position.offset));
+    return diagnostics;
+}
+
+
+struct Linter<'a> {
+    text: &'a str,
+    options: &'a LintOptions,
+    diagnostics: Vec<Diagnostic>,
+    /// the level of the last header, or 0 before the first header
+    header_level: uint,
+    top_level_headers: uint,
+    /// the marker of the first bullet list
+    bullet: Option<char>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, rule: LintRule, position: Position, message: String) {
+        if self.options.is_enabled(rule) {
+            self.diagnostics.push(Diagnostic { rule: rule, position: position, message: message });
+        }
+    }
+
+    /// the rules about the source lines, which are checked before tabs are expanded
+    fn check_lines(&mut self) {
+        let text = self.text;
+        let mut offset = 0;
+        for (i, line) in text.split('\n').enumerate() {
+            let line_offset = offset;
+            offset += line.len() + 1;
+            let line = line.trim_right_matches('\r');
+            let position_at = |index: uint| Position {
+                line: i + 1,
+                column: line.slice_to(index).chars().count() + 1,
+                offset: line_offset + index,
+            };
+
+            if let Some(index) = line.find('\t') {
+                self.report(LintRule::HardTab, position_at(index), "hard tab".to_string());
+            }
+            let content_length = line.trim_right().len();
+            let whitespace = line.slice_from(content_length);
+            // two spaces make a hard line break
+            if !whitespace.is_empty() && !(whitespace == "  " && content_length > 0) {
+                self.report(LintRule::TrailingWhitespace, position_at(content_length),
+                            "trailing whitespace".to_string());
+            }
+        }
+    }
+
+    fn check_document(&mut self, document: &Document) {
+        self.check_blocks(document.blocks.as_slice());
+        for footnote in document.footnotes.iter().chain(document.unreferenced_footnotes.iter()) {
+            self.check_blocks(footnote.blocks.as_slice());
+        }
+        self.check_references(document);
+    }
+
+    fn check_blocks(&mut self, blocks: &[Spanned<Block>]) {
+        for block in blocks.iter() {
+            let start = block.span.start;
+            match block.node {
+                Block::Header(level, ref text, _) => {
+                    if self.header_level > 0 && level > self.header_level + 1 {
+                        let message = format!("header of level {} after a header of level {}", level, self.header_level);
+                        self.report(LintRule::HeaderIncrement, start, message);
+                    }
+                    self.header_level = level;
+                    if level == 1 {
+                        self.top_level_headers += 1;
+                        if self.top_level_headers > 1 {
+                            self.report(LintRule::MultipleTopLevelHeaders, start,
+                                        "more than one header of level 1".to_string());
+                        }
+                    }
+                    self.check_inlines(text);
+                },
+                Block::Paragraph(ref text) => {
+                    self.check_inlines(text);
+                },
+                Block::Code(ref info, _) => {
+                    let has_language = match *info {
+                        Some(ref info) => !plain_text(info).trim().is_empty(),
+                        None => false,
+                    };
+                    let source = self.text.slice_from(start.offset);
+                    if !has_language && (source.starts_with("```") || source.starts_with("~~~")) {
+                        self.report(LintRule::FenceLanguage, start,
+                                    "fenced code block without a language".to_string());
+                    }
+                },
+                Block::BlockQuote(ref blocks) => {
+                    self.check_blocks(blocks.as_slice());
+                },
+                Block::List(_, ref items) => {
+                    if let Some(&ListType::Unordered(marker)) = items.first().map(|item| &item.listtype) {
+                        match self.bullet {
+                            None => self.bullet = Some(marker),
+                            Some(bullet) if bullet != marker => {
+                                let message = format!("bullet list marker '{}' instead of '{}'", marker, bullet);
+                                self.report(LintRule::ListMarkerStyle, start, message);
+                            },
+                            _ => {}
+                        }
+                    }
+                    for item in items.iter() {
+                        self.check_blocks(item.blocks.as_slice());
+                    }
+                },
+                Block::Table(_, ref header, ref rows) => {
+                    for cell in header.iter().chain(rows.iter().flat_map(|row| row.iter())) {
+                        self.check_inlines(cell);
+                    }
+                },
+                Block::Rule | Block::HTMLBlock(_) => {}
+            }
+        }
+    }
+
+    fn check_inlines(&mut self, text: &InlineText) {
+        for inline in text.iter() {
+            match inline.node {
+                Inline::Link(ref content, _, _) | Inline::Image(ref content, _, _) |
+                Inline::Emph(ref content) | Inline::Strong(ref content) | Inline::Strikethrough(ref content) => {
+                    self.check_inlines(content);
+                },
+                Inline::URIAutolink(_) | Inline::EmailAutolink(_) => {
+                    if !self.text.slice_from(inline.span.start.offset).starts_with("<") {
+                        self.report(LintRule::BareUrl, inline.span.start,
+                                    "URL without angle brackets".to_string());
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// the reference links with undefined labels and the unused definitions, which the parser
+    /// finds
+    fn check_references(&mut self, document: &Document) {
+        for reference in document.undefined_references.iter() {
+            self.report(LintRule::UndefinedReference, reference.span.start,
+                        format!("reference link with the undefined label '{}'", reference.node));
+        }
+        for reference in document.link_references.iter() {
+            if !reference.used {
+                self.report(LintRule::UnusedDefinition, reference.span.start,
+                            format!("link reference definition '{}' isn't used", reference.label));
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_lint() {
+    let markdown = "# Title\n\n### Details \n\n- a\n\n* b\t\n\n[x][nope] http://example.com  \n<http://example.com> [y]\n\n```\ncode\n```\n\n# Again\n\n[y]: /y\n[z]: /z";
+    let diagnostics: Vec<(uint, &str)> = lint(markdown, &Default::default()).iter()
+        .map(|d| (d.position.line, d.rule.name())).collect();
+    assert_eq!(diagnostics, vec![
+        (3, "header-increment"), (3, "trailing-whitespace"), (7, "list-marker-style"), (7, "hard-tab"),
+        (7, "trailing-whitespace"),
+        (9, "undefined-reference"), (9, "bare-url"), (12, "fence-language"),
+        (16, "multiple-top-level-headers"), (19, "unused-definition")]);
+
+    let mut options: LintOptions = Default::default();
+    options.disable(LintRule::HardTab);
+    options.disable(LintRule::UnusedDefinition);
+    assert_eq!(lint(markdown, &options).len(), 8);
+    assert_eq!(LintRule::from_name("bare-url"), Some(LintRule::BareUrl));
+
+    // escaped brackets aren't a reference link, and definitions are only used by their label
+    let markdown = "\\[x\\]\\[y\\] [t](/x) [u][a]\n\n[a]: /x\n[b]: /x\n[c]: /x";
+    let diagnostics: Vec<(uint, &str)> = lint(markdown, &Default::default()).iter()
+        .map(|d| (d.position.line, d.rule.name())).collect();
+    assert_eq!(diagnostics, vec![(4, "unused-definition"), (5, "unused-definition")]);
+}
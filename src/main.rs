@@ -1,24 +1,547 @@
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
+extern crate rustmark;
 
+use std::ascii::AsciiExt;
+use std::default::Default;
+use std::io;
 use std::io::fs::File;
+use std::os;
 
-mod parser;
 
+static USAGE: &'static str = "Usage: rustmark [OPTIONS] [FILE...]
+       rustmark fmt [FMT OPTIONS] [FILE...]
+       rustmark lint [LINT OPTIONS] [FILE...]
+
+Converts Markdown to HTML, normalized CommonMark or a JSON syntax tree. The input is read
+from FILE, or from stdin if no FILE is given. Several files are concatenated, separated by a
+blank line.
+
+Options:
+    -o, --output FILE    write the output to FILE instead of stdout
+    -t, --to FORMAT      output format, one of: html (default), commonmark, json
+        --gfm            enable the GitHub Flavored Markdown extensions: tables,
+                         strikethrough, task lists, autolinks, footnotes and the
+                         tagfilter for raw HTML
+        --front-matter   leave out YAML or TOML front matter at the start of the input
+        --header-ids     give headers GitHub style ids, or the id of a {#id} suffix
+        --toc            add a table of contents, at a paragraph [TOC] or at the start.
+                         This implies --header-ids
+        --smart          use curly quotes, en and em dashes and ellipses
+        --tab-width N    expand tabs to tab stops every N columns instead of 4
+        --no-raw-html    replace raw HTML by a comment instead of passing it through
+        --safe           leave out raw HTML and URLs with other schemes than those of
+                         --url-schemes, for untrusted input
+        --url-schemes LIST
+                         the comma separated URL schemes allowed with --safe instead of
+                         http,https,ftp,mailto,irc,ircs
+        --escape-html    show raw HTML that is left out as text
+    -h, --help           print this help and exit
+    -V, --version        print the version and exit
+
+The fmt command rewrites Markdown files in place as normalized CommonMark, with the GitHub
+Flavored Markdown extensions and front matter. Without a FILE, it formats stdin to stdout.
+
+Fmt options:
+        --check          only list the files that aren't formatted, with the first line
+                         that differs, and exit with status 3 if there are any
+        --config FILE    read style options from FILE, with lines like \"bullet = *\" for the
+                         options below without the leading \"--\" and \"true\" or \"false\"
+                         for the setext, renumber and reference-links switches
+        --bullet CHAR    the bullet list marker: - (default), * or +
+        --ordered-delimiter CHAR
+                         the delimiter after ordered list numbers: . (default) or )
+        --emphasis CHAR  the emphasis delimiter: * (default) or _
+        --strong CHAR    the strong emphasis delimiter: * (default) or _
+        --fence CHAR     the code fence character: ` (default) or ~
+        --rule CHAR      the horizontal rule character: - (default), * or _
+        --wrap N         fill paragraph lines up to N columns. 0 (default) keeps the lines
+        --setext         underline headers of level 1 and 2 instead of using ATX headers
+        --no-renumber    keep the numbers of ordered list items instead of numbering
+                         them consecutively
+        --inline-links   write all links as inline links and drop the link reference
+                         definitions
+
+The lint command checks Markdown files, or stdin, and lists the problems it finds as
+FILE:LINE:COLUMN: RULE: MESSAGE. It exits with status 3 if there are any. The rules are:
+header-increment, multiple-top-level-headers, trailing-whitespace, list-marker-style,
+undefined-reference, unused-definition, fence-language, bare-url and hard-tab.
+
+Lint options:
+        --config FILE    read lines like \"hard-tab = false\" from FILE, which enable or
+                         disable rules
+        --enable LIST    enable the comma separated rules
+        --disable LIST   disable the comma separated rules";
+
+// exit codes
+static EXIT_IO_ERROR: int = 1;
+static EXIT_USAGE_ERROR: int = 2;
+static EXIT_CHECK_FAILED: int = 3;
+
+
+enum OutputFormat {
+    HTML,
+    CommonMark,
+    JSON,
+}
+
+struct Options {
+    input_files: Vec<String>,
+    output_file: Option<String>,
+    format: OutputFormat,
+    parse_options: rustmark::ParseOptions,
+    render_options: rustmark::RenderOptions,
+}
+
+struct FormatOptions {
+    files: Vec<String>,
+    /// only report the files that aren't formatted
+    check: bool,
+    style: rustmark::CommonMarkOptions,
+}
+
+struct LintCommandOptions {
+    files: Vec<String>,
+    lint_options: rustmark::LintOptions,
+}
+
+enum Command {
+    Convert(Options),
+    Format(FormatOptions),
+    Lint(LintCommandOptions),
+    Help,
+    Version,
+}
+
+
+fn parse_format(name: &str) -> Result<OutputFormat, String> {
+    return match name {
+        "html" => Ok(OutputFormat::HTML),
+        "commonmark" => Ok(OutputFormat::CommonMark),
+        "json" => Ok(OutputFormat::JSON),
+        _ => Err(format!("unknown output format '{}'", name)),
+    }
+}
+
+fn parse_tab_width(value: &str) -> Result<uint, String> {
+    return match value.parse() {
+        Some(width) if width > 0 => Ok(width),
+        _ => Err(format!("invalid tab width '{}'", value)),
+    }
+}
+
+fn parse_url_schemes(value: &str) -> Vec<String> {
+    return value.split(',').map(|scheme| scheme.trim().to_ascii_lowercase())
+                .filter(|scheme| !scheme.is_empty()).collect();
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    if args.first().map(|arg| arg.as_slice()) == Some("fmt") {
+        return parse_format_args(args.slice_from(1));
+    }
+    if args.first().map(|arg| arg.as_slice()) == Some("lint") {
+        return parse_lint_args(args.slice_from(1));
+    }
+    let mut options = Options {
+        input_files: Vec::new(),
+        output_file: None,
+        format: OutputFormat::HTML,
+        parse_options: Default::default(),
+        render_options: Default::default(),
+    };
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+        match arg {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--gfm" => {
+                options.parse_options.tables = true;
+                options.parse_options.strikethrough = true;
+                options.parse_options.tasklists = true;
+                options.parse_options.autolinks = true;
+                options.parse_options.footnotes = true;
+                options.render_options.tagfilter = true;
+            },
+            "--front-matter" => options.parse_options.front_matter = true,
+            "--header-ids" => options.parse_options.header_ids = true,
+            "--toc" => {
+                options.parse_options.header_ids = true;
+                options.render_options.toc = true;
+            },
+            "--smart" => options.parse_options.smart_punctuation = true,
+            "--no-raw-html" => options.render_options.raw_html = false,
+            "--safe" => options.render_options.safe = true,
+            "--escape-html" => options.render_options.escape_raw_html = true,
+            "-o" | "--output" | "-t" | "--to" | "--tab-width" | "--url-schemes" => {
+                if i + 1 >= args.len() {
+                    return Err(format!("option '{}' needs an argument", arg));
+                }
+                let value = args[i + 1].clone();
+                match arg {
+                    "-o" | "--output" => options.output_file = Some(value),
+                    "-t" | "--to" => options.format = try!(parse_format(value.as_slice())),
+                    "--url-schemes" => options.render_options.url_schemes = parse_url_schemes(value.as_slice()),
+                    _ => options.parse_options.tab_width = try!(parse_tab_width(value.as_slice())),
+                }
+                i += 1;
+            },
+            "-" => options.input_files.push(arg.to_string()),
+            _ if arg.starts_with("-") => return Err(format!("unknown option '{}'", arg)),
+            _ => options.input_files.push(arg.to_string()),
+        }
+        i += 1;
+    }
+    return Ok(Command::Convert(options));
+}
+
+fn parse_format_args(args: &[String]) -> Result<Command, String> {
+    let mut options = FormatOptions {
+        files: Vec::new(),
+        check: false,
+        style: Default::default(),
+    };
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+        match arg {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--check" => options.check = true,
+            "--setext" => options.style.setext_headers = true,
+            "--no-renumber" => options.style.renumber = false,
+            "--inline-links" => options.style.reference_links = false,
+            "--config" | "--bullet" | "--ordered-delimiter" | "--emphasis" | "--strong" | "--fence" |
+            "--rule" | "--wrap" => {
+                if i + 1 >= args.len() {
+                    return Err(format!("option '{}' needs an argument", arg));
+                }
+                let value = args[i + 1].as_slice();
+                match arg {
+                    "--config" => try!(read_style_config(&mut options.style, value)),
+                    _ => try!(set_style_option(&mut options.style, arg.slice_from(2), value)),
+                }
+                i += 1;
+            },
+            _ if arg.starts_with("-") => return Err(format!("unknown option '{}'", arg)),
+            _ => options.files.push(arg.to_string()),
+        }
+        i += 1;
+    }
+    return Ok(Command::Format(options));
+}
+
+/// sets a style option of the fmt command, named like its command line option
+fn set_style_option(style: &mut rustmark::CommonMarkOptions, name: &str, value: &str) -> Result<(), String> {
+    match name {
+        "wrap" => {
+            style.line_width = match value.parse() {
+                Some(width) => width,
+                None => return Err(format!("invalid line width '{}'", value)),
+            };
+            return Ok(());
+        },
+        "setext" | "renumber" | "reference-links" => {
+            let on = match value {
+                "true" => true,
+                "false" => false,
+                _ => return Err(format!("invalid value '{}' for {}, expected true or false", value, name)),
+            };
+            match name {
+                "setext" => style.setext_headers = on,
+                "renumber" => style.renumber = on,
+                _ => style.reference_links = on,
+            }
+            return Ok(());
+        },
+        _ => {}
+    }
+
+    let choices = match name {
+        "bullet" => "-*+",
+        "ordered-delimiter" => ".)",
+        "emphasis" | "strong" => "*_",
+        "fence" => "`~",
+        "rule" => "-*_",
+        _ => return Err(format!("unknown style option '{}'", name)),
+    };
+    if value.char_len() != 1 || !choices.contains_char(value.char_at(0)) {
+        return Err(format!("invalid value '{}' for {}, expected one of: {}", value, name, choices));
+    }
+    let ch = value.char_at(0);
+    match name {
+        "bullet" => style.bullet = ch,
+        "ordered-delimiter" => style.ordered_delimiter = ch,
+        "emphasis" => style.emphasis = ch,
+        "strong" => style.strong = ch,
+        "fence" => style.fence = ch,
+        _ => style.rule = ch,
+    }
+    return Ok(());
+}
+
+/// reads the settings of a configuration file with lines like "name = value", together with
+/// their line numbers. Values may be quoted, and lines starting with "#" are comments
+fn read_config(filename: &str) -> Result<Vec<(uint, String, String)>, String> {
+    let config = try!(File::open(&Path::new(filename))
+        .and_then(|mut file| file.read_to_string())
+        .map_err(|e| format!("can't read '{}': {}", filename, e)));
+    let mut settings = Vec::new();
+    for (n, line) in config.as_slice().lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        let index = match line.find('=') {
+            Some(index) => index,
+            None => return Err(format!("{}:{}: expected a line like \"name = value\"", filename, n + 1)),
+        };
+        let value = line.slice_from(index + 1).trim();
+        let value = if value.len() >= 2 && (value.starts_with("\"") && value.ends_with("\"") ||
+                                            value.starts_with("'") && value.ends_with("'")) {
+            value.slice(1, value.len() - 1)
+        } else {
+            value
+        };
+        settings.push((n + 1, line.slice_to(index).trim().to_string(), value.to_string()));
+    }
+    return Ok(settings);
+}
+
+fn read_style_config(style: &mut rustmark::CommonMarkOptions, filename: &str) -> Result<(), String> {
+    for (line, name, value) in try!(read_config(filename)).into_iter() {
+        if let Err(message) = set_style_option(style, name.as_slice(), value.as_slice()) {
+            return Err(format!("{}:{}: {}", filename, line, message));
+        }
+    }
+    return Ok(());
+}
+
+fn parse_lint_args(args: &[String]) -> Result<Command, String> {
+    let mut options = LintCommandOptions {
+        files: Vec::new(),
+        lint_options: Default::default(),
+    };
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+        match arg {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--config" | "--enable" | "--disable" => {
+                if i + 1 >= args.len() {
+                    return Err(format!("option '{}' needs an argument", arg));
+                }
+                let value = args[i + 1].as_slice();
+                match arg {
+                    "--config" => try!(read_lint_config(&mut options.lint_options, value)),
+                    _ => {
+                        for name in value.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+                            try!(set_lint_rule(&mut options.lint_options, name, arg == "--enable"));
+                        }
+                    }
+                }
+                i += 1;
+            },
+            "-" => options.files.push(arg.to_string()),
+            _ if arg.starts_with("-") => return Err(format!("unknown option '{}'", arg)),
+            _ => options.files.push(arg.to_string()),
+        }
+        i += 1;
+    }
+    return Ok(Command::Lint(options));
+}
+
+fn set_lint_rule(options: &mut rustmark::LintOptions, name: &str, enabled: bool) -> Result<(), String> {
+    let rule = match rustmark::LintRule::from_name(name) {
+        Some(rule) => rule,
+        None => return Err(format!("unknown lint rule '{}'", name)),
+    };
+    if enabled {
+        options.enable(rule);
+    } else {
+        options.disable(rule);
+    }
+    return Ok(());
+}
+
+/// reads lines like "hard-tab = false", which enable or disable lint rules
+fn read_lint_config(options: &mut rustmark::LintOptions, filename: &str) -> Result<(), String> {
+    for (line, name, value) in try!(read_config(filename)).into_iter() {
+        let result = match value.as_slice() {
+            "true" => set_lint_rule(options, name.as_slice(), true),
+            "false" => set_lint_rule(options, name.as_slice(), false),
+            _ => Err(format!("invalid value '{}' for {}, expected true or false", value, name)),
+        };
+        if let Err(message) = result {
+            return Err(format!("{}:{}: {}", filename, line, message));
+        }
+    }
+    return Ok(());
+}
+
+
+/// reads all input files, or stdin if there are none, into one string
+fn read_input(input_files: &[String]) -> Result<String, String> {
+    if input_files.is_empty() {
+        return io::stdin().read_to_string().map_err(|e| format!("can't read from stdin: {}", e));
+    }
+
+    let mut input = String::new();
+    for filename in input_files.iter() {
+        let content = if filename.as_slice() == "-" {
+            io::stdin().read_to_string().map_err(|e| format!("can't read from stdin: {}", e))
+        } else {
+            File::open(&Path::new(filename.as_slice()))
+                .and_then(|mut file| file.read_to_string())
+                .map_err(|e| format!("can't read '{}': {}", filename, e))
+        };
+        let content = try!(content);
+        if !input.is_empty() {
+            if !input.ends_with("\n") {
+                input.push('\n');
+            }
+            input.push('\n');
+        }
+        input.push_str(content.as_slice());
+    }
+    return Ok(input);
+}
+
+fn write_output(output_file: &Option<String>, output: &str) -> Result<(), String> {
+    return match *output_file {
+        Some(ref filename) => {
+            File::create(&Path::new(filename.as_slice()))
+                .and_then(|mut file| file.write_str(output))
+                .map_err(|e| format!("can't write '{}': {}", filename, e))
+        },
+        None => {
+            io::stdout().write_str(output).map_err(|e| format!("can't write to stdout: {}", e))
+        }
+    }
+}
+
+fn convert(options: &Options) -> Result<(), String> {
+    let input = try!(read_input(options.input_files.as_slice()));
+    let document = rustmark::parse_with_options(input.as_slice(), &options.parse_options);
+    let output = match options.format {
+        OutputFormat::HTML => {
+            let html = rustmark::render_html_with_options(&document, &options.render_options);
+            // without a [TOC] marker, the table of contents goes at the start
+            if options.render_options.toc && !document.blocks.iter().any(|block| rustmark::is_toc_marker(&block.node)) {
+                let toc = rustmark::table_of_contents(&document);
+                rustmark::render_toc_html(toc.as_slice()) + html.as_slice()
+            } else {
+                html
+            }
+        },
+        OutputFormat::CommonMark => rustmark::render_commonmark(&document),
+        OutputFormat::JSON => rustmark::render_json(&document),
+    };
+    return write_output(&options.output_file, output.as_slice());
+}
+
+
+fn format_markdown(input: &str, style: &rustmark::CommonMarkOptions) -> String {
+    let mut parse_options = rustmark::ParseOptions::gfm();
+    parse_options.front_matter = true;
+    let document = rustmark::parse_with_options(input, &parse_options);
+    return rustmark::render_commonmark_with_options(&document, style);
+}
+
+/// the number of the first line that differs between two texts
+fn first_difference(a: &str, b: &str) -> uint {
+    return a.lines().zip(b.lines()).take_while(|&(line_a, line_b)| line_a == line_b).count() + 1;
+}
+
+/// formats the files in place, or stdin to stdout. With the check option, only reports the files
+/// that aren't formatted. Returns whether all files were formatted already
+fn format_files(options: &FormatOptions) -> Result<bool, String> {
+    let stdin = vec!["-".to_string()];
+    let files = if options.files.is_empty() { stdin.as_slice() } else { options.files.as_slice() };
+    let mut all_formatted = true;
+    for filename in files.iter() {
+        let input = try!(read_input(&[filename.clone()]));
+        let output = format_markdown(input.as_slice(), &options.style);
+        let is_stdin = filename.as_slice() == "-";
+        if output == input && !is_stdin {
+            continue;
+        }
+        if options.check {
+            if output != input {
+                let name = if is_stdin { "<stdin>" } else { filename.as_slice() };
+                println!("{}: not formatted, first difference in line {}", name,
+                         first_difference(input.as_slice(), output.as_slice()));
+                all_formatted = false;
+            }
+        } else {
+            let output_file = if is_stdin { None } else { Some(filename.clone()) };
+            try!(write_output(&output_file, output.as_slice()));
+        }
+    }
+    return Ok(all_formatted);
+}
+
+/// lists the problems in the files, or in stdin. Returns whether there are none
+fn lint_files(options: &LintCommandOptions) -> Result<bool, String> {
+    let stdin = vec!["-".to_string()];
+    let files = if options.files.is_empty() { stdin.as_slice() } else { options.files.as_slice() };
+    let mut no_problems = true;
+    for filename in files.iter() {
+        let input = try!(read_input(&[filename.clone()]));
+        let name = if filename.as_slice() == "-" { "<stdin>" } else { filename.as_slice() };
+        for diagnostic in rustmark::lint(input.as_slice(), &options.lint_options).iter() {
+            println!("{}:{}:{}: {}: {}", name, diagnostic.position.line, diagnostic.position.column,
+                     diagnostic.rule.name(), diagnostic.message);
+            no_problems = false;
+        }
+    }
+    return Ok(no_problems);
+}
+
+
+fn exit_with_error(message: &str, exit_code: int) {
+    let _ = io::stderr().write_line(format!("rustmark: {}", message).as_slice());
+    os::set_exit_status(exit_code);
+}
 
 fn main() {
-    let program_args: Vec<String> = std::os::args();
-    if program_args.len() < 2 {
-        println!("Wie wärsn mit ner Datei, hä?");
-        return;
+    let args = os::args();
+    match parse_args(args.slice_from(1)) {
+        Err(message) => {
+            exit_with_error(format!("{}\n\n{}", message, USAGE).as_slice(), EXIT_USAGE_ERROR);
+        },
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+        },
+        Ok(Command::Version) => {
+            println!("rustmark {}", env!("CARGO_PKG_VERSION"));
+        },
+        Ok(Command::Convert(options)) => {
+            if let Err(message) = convert(&options) {
+                exit_with_error(message.as_slice(), EXIT_IO_ERROR);
+            }
+        },
+        Ok(Command::Format(options)) => {
+            match format_files(&options) {
+                Err(message) => exit_with_error(message.as_slice(), EXIT_IO_ERROR),
+                Ok(false) => os::set_exit_status(EXIT_CHECK_FAILED),
+                Ok(true) => {}
+            }
+        },
+        Ok(Command::Lint(options)) => {
+            match lint_files(&options) {
+                Err(message) => exit_with_error(message.as_slice(), EXIT_IO_ERROR),
+                Ok(false) => os::set_exit_status(EXIT_CHECK_FAILED),
+                Ok(true) => {}
+            }
+        }
     }
-    let md_filename:&String = &program_args[1];
-    let mut md_file = File::open(&Path::new(md_filename)).ok().expect("Sicher, dass das ne Datei ist?");
-    let md_string:String = md_file.read_to_string().ok().expect("Kann nix lesen");
+}
 
-    let result = parser::parse_markdown(md_string);
 
-    println!("{}", result);
+#[test]
+fn test_format_markdown() {
+    let style = Default::default();
+    // tabs in code and unreferenced footnotes are kept
+    let markdown = "Text.[^used]\n\n```make\nall:\n\tcc main.c\n```\n\n[^used]: Used.\n\n[^unused]: Unused.\n";
+    assert_eq!(format_markdown(markdown, &style).as_slice(), markdown);
+    assert_eq!(format_markdown("\tfn main() {\n\t\treturn;\n\t}\n", &style).as_slice(),
+               "```\nfn main() {\n\treturn;\n}\n```\n");
 }
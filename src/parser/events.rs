@@ -0,0 +1,402 @@
+use std::collections::{HashSet, RingBuf};
+use std::default::Default;
+
+use super::{Alignment, Block, Footnote, ListType, ParseOptions, ParseState, Spanned};
+use super::inline::{Inline, InlineText, LinkReferenceMap, autolink_destination, normalize_label};
+use super::front_matter::FrontMatter;
+use super::preprocess;
+use super::preprocess::SourceMap;
+
+
+/// an element that is started and ended by events
+#[derive(Show,Clone,PartialEq)]
+pub enum Tag {
+    Paragraph,
+    /// a header with its level (1 to 6) and id, which is only given with the header ids option
+    Header(uint, Option<String>),
+    BlockQuote,
+    /// a code block with its info string, or `None` for indented code. The code follows as a
+    /// single `Event::Text`
+    CodeBlock(Option<String>),
+    /// a list with the list type of its first item, which is tight if none of its items are
+    /// separated by blank lines
+    List(ListType, bool),
+    Item,
+    /// a table with the alignments of its columns
+    Table(Vec<Alignment>),
+    /// the header row of a table
+    TableHead,
+    /// a body row of a table
+    TableRow,
+    TableCell,
+    Emph,
+    Strong,
+    Strikethrough,
+    /// a link with its destination and title. Autolinks are links with an empty title
+    Link(String, String),
+    /// an image with its source and title. The description is given by the events in between
+    Image(String, String),
+    /// a footnote with its label and number. The referenced footnotes follow the last block of
+    /// the document, in the order of their numbers
+    FootnoteDefinition(String, uint),
+    /// a footnote that isn't referenced, with its label. The unreferenced footnotes follow the
+    /// referenced ones, in the order of the source
+    UnreferencedFootnoteDefinition(String),
+}
+
+/// an event of the pull parser
+#[derive(Show,Clone,PartialEq)]
+pub enum Event {
+    Start(Tag),
+    End(Tag),
+    Text(String),
+    /// a code span
+    Code(String),
+    /// a raw HTML block or inline tag
+    Html(String),
+    /// a horizontal rule
+    Rule,
+    SoftBreak,
+    HardBreak,
+    /// the checkbox at the start of a task list item, and whether it is checked
+    TaskListMarker(bool),
+    /// a reference to a footnote with its label and number
+    FootnoteReference(String, uint),
+}
+
+
+/// a pull parser, which iterates over the events of a document
+///
+/// Only one top level block is parsed at a time, so the whole document tree is never built.
+pub struct Parser {
+    lines: Vec<String>,
+    source_map: SourceMap,
+    pos: uint,
+    refs: LinkReferenceMap,
+    footnotes: Vec<Footnote>,
+    footnote_labels: HashSet<String>,
+    /// the normalized labels of the referenced footnotes, in the order of their numbers
+    referenced_footnotes: Vec<String>,
+    /// the number of footnote definitions that have been parsed
+    parsed_footnotes: uint,
+    front_matter: Option<FrontMatter>,
+    /// the header ids so far, which the following headers mustn't use
+    header_ids: HashSet<String>,
+    options: ParseOptions,
+    events: RingBuf<Event>,
+}
+
+impl Parser {
+    pub fn new(text: &str) -> Parser {
+        return Parser::with_options(text, &Default::default());
+    }
+
+    pub fn with_options(text: &str, options: &ParseOptions) -> Parser {
+        let (lines, source_map) = preprocess::preprocess_text(text, options.tab_width);
+        let (front_matter, first_line) = super::find_front_matter(lines.as_slice(), &source_map, options);
+
+        // link reference definitions and footnote definitions may follow the links and
+        // references that use them, so they are collected in advance. That takes a parse of the
+        // whole document, which is skipped when no line contains the "]:" of a definition.
+        // Parsing list items changes the lines, so that uses a copy of them
+        let has_definitions = lines.iter().skip(first_line).any(|line| line.as_slice().contains("]:"));
+        let (refs, footnotes, footnote_labels) = if !has_definitions {
+            (Default::default(), Vec::new(), HashSet::new())
+        } else {
+            let mut lines_copy = lines.clone();
+            let mut st = ParseState::new(lines_copy.as_mut_slice(), &source_map, options);
+            st.pos = first_line;
+            while st.parse_top_level_block().is_some() {}
+            let footnote_labels = st.footnote_labels();
+            (st.link_reference_defs, st.footnote_defs, footnote_labels)
+        };
+
+        Parser {
+            lines: lines,
+            source_map: source_map,
+            pos: first_line,
+            refs: refs,
+            footnotes: footnotes,
+            footnote_labels: footnote_labels,
+            referenced_footnotes: Vec::new(),
+            parsed_footnotes: 0,
+            front_matter: front_matter,
+            header_ids: HashSet::new(),
+            options: options.clone(),
+            events: RingBuf::new()
+        }
+    }
+
+    /// the front matter at the start of the document, if the option is enabled
+    pub fn front_matter(&self) -> Option<&FrontMatter> {
+        return self.front_matter.as_ref();
+    }
+
+    /// the next referenced footnote, after the last block of the document
+    fn next_footnote(&mut self) -> Option<Footnote> {
+        if self.parsed_footnotes >= self.referenced_footnotes.len() {
+            return None;
+        }
+        let index = {
+            let label = &self.referenced_footnotes[self.parsed_footnotes];
+            self.footnotes.iter().position(|f| normalize_label(f.label.as_slice()) == *label).unwrap()
+        };
+        self.parsed_footnotes += 1;
+        let mut footnote = self.footnotes.remove(index);
+        footnote.number = self.parsed_footnotes;
+        return Some(footnote);
+    }
+
+    /// numbers the footnote references of the queued events in the order of their first
+    /// occurrence
+    fn number_footnote_references(&mut self) {
+        for event in self.events.iter_mut() {
+            if let Event::FootnoteReference(ref label, ref mut number) = *event {
+                let normalized_label = normalize_label(label.as_slice());
+                *number = match self.referenced_footnotes.iter().position(|l| *l == normalized_label) {
+                    Some(index) => index + 1,
+                    None => {
+                        self.referenced_footnotes.push(normalized_label);
+                        self.referenced_footnotes.len()
+                    }
+                };
+            }
+        }
+    }
+}
+
+impl Iterator for Parser {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if self.events.is_empty() {
+            let block = {
+                let mut st = ParseState::new(self.lines.as_mut_slice(), &self.source_map, &self.options);
+                st.pos = self.pos;
+                let block = st.parse_top_level_block();
+                self.pos = st.pos;
+                block
+            };
+            if let Some(block) = block {
+                let mut blocks = vec![block];
+                super::parse_inline_content(&mut blocks, &self.refs, &mut Default::default(),
+                                            &self.footnote_labels, &self.source_map, &self.options);
+                if self.options.header_ids {
+                    super::assign_header_ids(blocks.as_mut_slice(), &mut self.header_ids);
+                }
+                push_block_events(&mut self.events, blocks.pop().unwrap().node);
+            } else if let Some(mut footnote) = self.next_footnote() {
+                super::parse_inline_content(&mut footnote.blocks, &self.refs, &mut Default::default(),
+                                            &self.footnote_labels, &self.source_map, &self.options);
+                let tag = Tag::FootnoteDefinition(footnote.label, footnote.number);
+                self.events.push_back(Event::Start(tag.clone()));
+                push_blocks_events(&mut self.events, footnote.blocks);
+                self.events.push_back(Event::End(tag));
+            } else if !self.footnotes.is_empty() {
+                // the footnotes left are unreferenced, and the references inside of them don't
+                // count, so they keep the number 0
+                let mut footnote = self.footnotes.remove(0);
+                super::parse_inline_content(&mut footnote.blocks, &self.refs, &mut Default::default(),
+                                            &self.footnote_labels, &self.source_map, &self.options);
+                let tag = Tag::UnreferencedFootnoteDefinition(footnote.label);
+                self.events.push_back(Event::Start(tag.clone()));
+                push_blocks_events(&mut self.events, footnote.blocks);
+                self.events.push_back(Event::End(tag));
+                return self.events.pop_front();
+            }
+            self.number_footnote_references();
+        }
+        return self.events.pop_front();
+    }
+}
+
+
+fn push_blocks_events(events: &mut RingBuf<Event>, blocks: Vec<Spanned<Block>>) {
+    for block in blocks.into_iter() {
+        push_block_events(events, block.node);
+    }
+}
+
+fn push_block_events(events: &mut RingBuf<Event>, block: Block) {
+    match block {
+        Block::Rule => {
+            events.push_back(Event::Rule);
+        },
+        Block::Header(level, text, id) => {
+            events.push_back(Event::Start(Tag::Header(level, id.clone())));
+            push_inlines_events(events, text);
+            events.push_back(Event::End(Tag::Header(level, id)));
+        },
+        Block::Paragraph(text) => {
+            events.push_back(Event::Start(Tag::Paragraph));
+            push_inlines_events(events, text);
+            events.push_back(Event::End(Tag::Paragraph));
+        },
+        Block::Code(info, code) => {
+            // the info string is a single text node
+            let info = info.map(|text| match text.into_iter().next() {
+                Some(Spanned { node: Inline::Text(info), .. }) => info,
+                _ => "".to_string(),
+            });
+            events.push_back(Event::Start(Tag::CodeBlock(info.clone())));
+            events.push_back(Event::Text(code));
+            events.push_back(Event::End(Tag::CodeBlock(info)));
+        },
+        Block::BlockQuote(blocks) => {
+            events.push_back(Event::Start(Tag::BlockQuote));
+            push_blocks_events(events, *blocks);
+            events.push_back(Event::End(Tag::BlockQuote));
+        },
+        Block::HTMLBlock(html) => {
+            events.push_back(Event::Html(html));
+        },
+        Block::List(tight, items) => {
+            let list_type = match items.first() {
+                Some(item) => item.listtype.clone(),
+                None => ListType::Unordered('-'),
+            };
+            events.push_back(Event::Start(Tag::List(list_type.clone(), tight)));
+            for item in items.into_iter() {
+                events.push_back(Event::Start(Tag::Item));
+                if let Some(checked) = item.checked {
+                    events.push_back(Event::TaskListMarker(checked));
+                }
+                push_blocks_events(events, *item.blocks);
+                events.push_back(Event::End(Tag::Item));
+            }
+            events.push_back(Event::End(Tag::List(list_type, tight)));
+        },
+        Block::Table(alignments, header, rows) => {
+            events.push_back(Event::Start(Tag::Table(alignments.clone())));
+            push_table_row_events(events, Tag::TableHead, header);
+            for row in rows.into_iter() {
+                push_table_row_events(events, Tag::TableRow, row);
+            }
+            events.push_back(Event::End(Tag::Table(alignments)));
+        },
+    }
+}
+
+fn push_table_row_events(events: &mut RingBuf<Event>, tag: Tag, cells: Vec<InlineText>) {
+    events.push_back(Event::Start(tag.clone()));
+    for cell in cells.into_iter() {
+        events.push_back(Event::Start(Tag::TableCell));
+        push_inlines_events(events, cell);
+        events.push_back(Event::End(Tag::TableCell));
+    }
+    events.push_back(Event::End(tag));
+}
+
+fn push_inlines_events(events: &mut RingBuf<Event>, text: InlineText) {
+    for inline in text.into_iter() {
+        push_inline_events(events, inline.node);
+    }
+}
+
+fn push_inline_events(events: &mut RingBuf<Event>, inline: Inline) {
+    match inline {
+        Inline::URIAutolink(uri) => {
+            let tag = Tag::Link(autolink_destination(uri.as_slice()), "".to_string());
+            events.push_back(Event::Start(tag.clone()));
+            events.push_back(Event::Text(uri));
+            events.push_back(Event::End(tag));
+        },
+        Inline::EmailAutolink(address) => {
+            let tag = Tag::Link(format!("mailto:{}", address), "".to_string());
+            events.push_back(Event::Start(tag.clone()));
+            events.push_back(Event::Text(address));
+            events.push_back(Event::End(tag));
+        },
+        Inline::HTMLTag(html) => {
+            events.push_back(Event::Html(html));
+        },
+        Inline::CodeSpan(code) => {
+            events.push_back(Event::Code(code));
+        },
+        Inline::Link(content, destination, title) => {
+            let tag = Tag::Link(destination, title);
+            events.push_back(Event::Start(tag.clone()));
+            push_inlines_events(events, content);
+            events.push_back(Event::End(tag));
+        },
+        Inline::Image(content, source, title) => {
+            let tag = Tag::Image(source, title);
+            events.push_back(Event::Start(tag.clone()));
+            push_inlines_events(events, content);
+            events.push_back(Event::End(tag));
+        },
+        Inline::Emph(content) => {
+            events.push_back(Event::Start(Tag::Emph));
+            push_inlines_events(events, content);
+            events.push_back(Event::End(Tag::Emph));
+        },
+        Inline::Strong(content) => {
+            events.push_back(Event::Start(Tag::Strong));
+            push_inlines_events(events, content);
+            events.push_back(Event::End(Tag::Strong));
+        },
+        Inline::Strikethrough(content) => {
+            events.push_back(Event::Start(Tag::Strikethrough));
+            push_inlines_events(events, content);
+            events.push_back(Event::End(Tag::Strikethrough));
+        },
+        Inline::FootnoteReference(label, number) => {
+            events.push_back(Event::FootnoteReference(label, number));
+        },
+        Inline::Text(text) => {
+            events.push_back(Event::Text(text));
+        },
+        Inline::HardBreak => {
+            events.push_back(Event::HardBreak);
+        },
+        Inline::SoftBreak => {
+            events.push_back(Event::SoftBreak);
+        },
+    }
+}
+
+
+
+#[test]
+fn test_events() {
+    let events: Vec<Event> = Parser::new("# foo\n\n> [bar] *baz*\n\n[bar]: /url").collect();
+    assert_eq!(events, vec![
+        Event::Start(Tag::Header(1, None)),
+        Event::Text("foo".to_string()),
+        Event::End(Tag::Header(1, None)),
+        Event::Start(Tag::BlockQuote),
+        Event::Start(Tag::Paragraph),
+        Event::Start(Tag::Link("/url".to_string(), "".to_string())),
+        Event::Text("bar".to_string()),
+        Event::End(Tag::Link("/url".to_string(), "".to_string())),
+        Event::Text(" ".to_string()),
+        Event::Start(Tag::Emph),
+        Event::Text("baz".to_string()),
+        Event::End(Tag::Emph),
+        Event::End(Tag::Paragraph),
+        Event::End(Tag::BlockQuote),
+    ]);
+}
+
+#[test]
+fn test_footnote_events() {
+    let events: Vec<Event> = Parser::with_options("Text[^a].\n\n[^b]: Unused\n[^a]: Used",
+                                                  &ParseOptions::gfm()).collect();
+    assert_eq!(events, vec![
+        Event::Start(Tag::Paragraph),
+        Event::Text("Text".to_string()),
+        Event::FootnoteReference("a".to_string(), 1),
+        Event::Text(".".to_string()),
+        Event::End(Tag::Paragraph),
+        Event::Start(Tag::FootnoteDefinition("a".to_string(), 1)),
+        Event::Start(Tag::Paragraph),
+        Event::Text("Used".to_string()),
+        Event::End(Tag::Paragraph),
+        Event::End(Tag::FootnoteDefinition("a".to_string(), 1)),
+        Event::Start(Tag::UnreferencedFootnoteDefinition("b".to_string())),
+        Event::Start(Tag::Paragraph),
+        Event::Text("Unused".to_string()),
+        Event::End(Tag::Paragraph),
+        Event::End(Tag::UnreferencedFootnoteDefinition("b".to_string())),
+    ]);
+}
@@ -0,0 +1,166 @@
+use regex::Regex;
+
+use super::Span;
+use super::preprocess::SourceMap;
+
+
+static RX_YAML_KEY_VALUE: Regex = regex!(r"^([^\s#:'\x22][^:]*?|'[^']*'|\x22[^\x22]*\x22) *:(?: +(.*?))? *$");
+static RX_TOML_KEY_VALUE: Regex = regex!(r"^ *([A-Za-z0-9_.-]+|\x22[^\x22]*\x22|'[^']*') *= *(.*?) *$");
+static RX_TOML_TABLE: Regex = regex!(r"^ *\[\[? *([^\]]*?) *\]\]? *$");
+
+/// the format of front matter, given by its delimiter lines
+#[derive(Show,Clone,Copy,PartialEq)]
+pub enum FrontMatterFormat {
+    /// between "---" lines. The closing line may also be "..."
+    YAML,
+    /// between "+++" lines
+    TOML,
+}
+
+/// metadata at the very start of a document, which isn't part of its content
+#[derive(Show,Clone)]
+pub struct FrontMatter {
+    pub format: FrontMatterFormat,
+    /// the text between the delimiter lines, unchanged
+    pub raw: String,
+    /// the keys and values, in the order of the source
+    ///
+    /// Quoted values are unquoted, other values are kept as they are. A YAML key without a value
+    /// gets the indented lines below it, and TOML keys in tables are prefixed with the name of the
+    /// table, e.g. "server.port".
+    pub values: Vec<(String, String)>,
+    /// the span including the delimiter lines
+    pub span: Span,
+}
+
+impl FrontMatter {
+    /// the value of a key, if it is given
+    pub fn get(&self, key: &str) -> Option<&str> {
+        return self.values.iter().find(|&&(ref k, _)| k.as_slice() == key).map(|&(_, ref v)| v.as_slice());
+    }
+}
+
+
+/// finds front matter in the first lines of a document, and also returns the number of lines it
+/// takes
+///
+/// Without a closing delimiter line, there is no front matter.
+pub fn parse_front_matter(lines: &[String], source_map: &SourceMap) -> Option<(FrontMatter, uint)> {
+    let format = match lines.first().map(|line| line.as_slice().trim_right()) {
+        Some("---") => FrontMatterFormat::YAML,
+        Some("+++") => FrontMatterFormat::TOML,
+        _ => return None,
+    };
+    let end = lines.iter().skip(1).position(|line| {
+        let line = line.as_slice().trim_right();
+        match format {
+            FrontMatterFormat::YAML => line == "---" || line == "...",
+            FrontMatterFormat::TOML => line == "+++",
+        }
+    });
+    let end = match end {
+        Some(index) => index + 1,
+        None => return None,
+    };
+
+    let raw_lines: Vec<&str> = range(1, end).map(|line| source_map.line(line)).collect();
+    let mut raw = raw_lines.connect("\n");
+    if !raw_lines.is_empty() {
+        raw.push('\n');
+    }
+    let values = match format {
+        FrontMatterFormat::YAML => yaml_values(raw_lines.as_slice()),
+        FrontMatterFormat::TOML => toml_values(raw_lines.as_slice()),
+    };
+    let span = Span { start: source_map.position(0, 0), end: source_map.position_from_end(end, 0) };
+    let front_matter = FrontMatter { format: format, raw: raw, values: values, span: span };
+    return Some((front_matter, end + 1));
+}
+
+
+/// the top level keys of YAML front matter. Nested mappings and lists are the value of their key
+fn yaml_values(lines: &[&str]) -> Vec<(String, String)> {
+    let mut values: Vec<(String, String)> = Vec::new();
+    for line in lines.iter() {
+        if line.trim().is_empty() || line.trim_left().starts_with("#") {
+            continue;
+        }
+        if line.starts_with(" ") || line.starts_with("\t") || line.starts_with("-") {
+            // an indented line continues the value of the last key
+            if let Some(&mut (_, ref mut value)) = values.last_mut() {
+                if !value.is_empty() {
+                    value.push('\n');
+                }
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some(cap) = RX_YAML_KEY_VALUE.captures(*line) {
+            let key = unquote(cap.at(1).unwrap());
+            let value = strip_yaml_comment(cap.at(2).unwrap_or(""));
+            values.push((key, unquote(value)));
+        }
+    }
+    return values;
+}
+
+/// the keys of TOML front matter, prefixed with the names of their tables
+fn toml_values(lines: &[&str]) -> Vec<(String, String)> {
+    let mut values = Vec::new();
+    let mut table = "".to_string();
+    for line in lines.iter() {
+        if line.trim().is_empty() || line.trim_left().starts_with("#") {
+            continue;
+        }
+        if let Some(cap) = RX_TOML_TABLE.captures(*line) {
+            table = cap.at(1).unwrap().to_string();
+        } else if let Some(cap) = RX_TOML_KEY_VALUE.captures(*line) {
+            let key = unquote(cap.at(1).unwrap());
+            let key = if table.is_empty() { key } else { format!("{}.{}", table, key) };
+            values.push((key, unquote(cap.at(2).unwrap())));
+        }
+    }
+    return values;
+}
+
+/// a YAML value without a trailing comment, which starts with " #" outside of quotes
+fn strip_yaml_comment(value: &str) -> &str {
+    // the comment can only start after the closing quote
+    let quote_end = if value.starts_with("'") || value.starts_with("\"") {
+        match value.slice_from(1).find(value.char_at(0)) {
+            Some(index) => index + 2,
+            None => return value,
+        }
+    } else {
+        0
+    };
+    return match value.slice_from(quote_end).find_str(" #") {
+        Some(index) => value.slice_to(quote_end + index).trim_right(),
+        None => value,
+    }
+}
+
+/// a string without surrounding quotes. Backslash escapes are only resolved in double quotes
+fn unquote(s: &str) -> String {
+    if s.len() >= 2 && s.starts_with("'") && s.ends_with("'") {
+        return s.slice(1, s.len() - 1).replace("''", "'");
+    }
+    if s.len() >= 2 && s.starts_with("\"") && s.ends_with("\"") {
+        let mut result = String::new();
+        let mut chars = s.slice(1, s.len() - 1).chars();
+        loop {
+            match chars.next() {
+                None => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => result.push('\n'),
+                    Some('t') => result.push('\t'),
+                    Some(c) => result.push(c),
+                    None => result.push('\\'),
+                },
+                Some(c) => result.push(c),
+            }
+        }
+        return result;
+    }
+    return s.to_string();
+}
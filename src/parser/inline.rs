@@ -1,282 +1,1287 @@
 
-use std::str::CharRange;
+use std::char;
+use std::collections::{HashMap, HashSet};
+use std::iter::repeat;
 use regex::Regex;
 
+use super::{ParseOptions, Position, Span, Spanned};
+use super::entities;
+use super::preprocess::SourceMap;
+
 static RX_ABSOLUTE_URI: Regex = regex!(r"<(?i:coap|doi|javascript|aaa|aaas|about|acap|cap|cid|crid|data|dav|dict|dns|file|ftp|geo|go|gopher|h323|http|https|iax|icap|im|imap|info|ipp|iris|iris\.beep|iris\.xpc|iris\.xpcs|iris\.lwz|ldap|mailto|mid|msrp|msrps|mtqp|mupdate|news|nfs|ni|nih|nntp|opaquelocktoken|pop|pres|rtsp|service|session|shttp|sieve|sip|sips|sms|snmp|soap\.beep|soap\.beeps|tag|tel|telnet|tftp|thismessage|tn3270|tip|tv|urn|vemmi|ws|wss|xcon|xcon-userid|xmlrpc\.beep|xmlrpc\.beeps|xmpp|z39\.50r|z39\.50s|adiumxtra|afp|afs|aim|apt|attachment|aw|beshare|bitcoin|bolo|callto|chrome|chrome-extension|com-eventbrite-attendee|content|cvs|dlna-playsingle|dlna-playcontainer|dtn|dvb|ed2k|facetime|feed|finger|fish|gg|git|gizmoproject|gtalk|hcp|icon|ipn|irc|irc6|ircs|itms|jar|jms|keyparc|lastfm|ldaps|magnet|maps|market|message|mms|ms-help|msnim|mumble|mvn|notes|oid|palm|paparazzi|platform|proxy|psyc|query|res|resource|rmi|rsync|rtmp|secondlife|sftp|sgn|skype|smb|soldat|spotify|ssh|steam|svn|teamspeak|things|udp|unreal|ut2004|ventrilo|view-source|webcal|wtai|wyciwyg|xfire|xri|ymsgr):[^<> ]+>");
 
 static RX_EMAIL_ADDRESS: Regex = regex!(r"<[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*>");
 
-#[derive(Show)]
-enum Inline {
+static RX_ENTITY: Regex = regex!(r"^&(?:#[xX]([0-9a-fA-F]{1,6})|#([0-9]{1,7})|([a-zA-Z][a-zA-Z0-9]{1,31}));");
+
+// extended autolinks of GitHub Flavored Markdown, which are found in the text without angle brackets
+static RX_EXTENDED_URL: Regex = regex!(r"^(?:(?:https?|ftp)://|www\.)[a-zA-Z0-9_-]+(?:\.[a-zA-Z0-9_-]+)*[^\s<]*");
+static RX_EXTENDED_EMAIL: Regex = regex!(r"^[a-zA-Z0-9.+_-]+@[a-zA-Z0-9_-]+(?:\.[a-zA-Z0-9_-]+)+");
+static RX_DOMAIN: Regex = regex!(r"^(?:(?:https?|ftp)://)?([a-zA-Z0-9_-]+(?:\.[a-zA-Z0-9_-]+)*)");
+
+// open tag, closing tag, comment, processing instruction, declaration or CDATA section
+static RX_HTML_TAG: Regex = regex!(r#"^(?:<[A-Za-z][A-Za-z0-9-]*(?:\s+[a-zA-Z_:][a-zA-Z0-9_.:-]*(?:\s*=\s*(?:[^"'=<>`\x00-\x20]+|'[^']*'|"[^"]*"))?)*\s*/?>|</[A-Za-z][A-Za-z0-9-]*\s*>|<!--(?:-?[^>-](?:-?[^-])*)?-->|<\?(?s:.*?)\?>|<![A-Z]+\s+[^>]*>|<!\[CDATA\[(?s:.*?)\]\]>)"#);
+
+/// an inline element inside of paragraphs and headers
+#[derive(Show,Clone)]
+pub enum Inline {
+    /// an absolute URI in angle brackets, e.g. `<http://example.com>`, or with the autolink
+    /// extension an URL or `www.` link in the text. See `autolink_destination`
     URIAutolink(String),
+    /// an email address in angle brackets, e.g. `<foo@example.com>`, or with the autolink
+    /// extension in the text
     EmailAutolink(String),
+    /// a raw HTML tag, which is passed through unchanged
     HTMLTag(String),
     CodeSpan(String),
-    Link(Box<Inline>, String, String),
-    Image(Box<Inline>, String, String),
-    Emph(Box<Inline>),
-    Strong(Box<Inline>),
+    /// a link with its text, destination and title
+    Link(InlineText, String, String),
+    /// an image with its description, source and title
+    Image(InlineText, String, String),
+    Emph(InlineText),
+    Strong(InlineText),
+    /// text between "~~", with the strikethrough extension
+    Strikethrough(InlineText),
+    /// a reference to a footnote with its label and number, with the footnotes extension
+    FootnoteReference(String, uint),
     Text(String),
+    /// a line break inside of a paragraph, e.g. after two trailing spaces or a backslash
+    HardBreak,
+    /// an ordinary line break inside of a paragraph
+    SoftBreak,
+}
+
+/// the inline content of a block
+pub type InlineText = Vec<Spanned<Inline>>;
+
+
+/// link reference definitions, from the normalized label to destination and title
+pub type LinkReferenceMap = HashMap<String, (String, String)>;
+
+/// how the inline content of a document uses the link reference definitions
+#[derive(Show,Default)]
+pub struct ReferenceUsage {
+    /// the normalized labels of the definitions that links and images refer to
+    pub used_labels: HashSet<String>,
+    /// the labels of full and collapsed reference links that aren't defined, which are left as
+    /// text
+    pub undefined: Vec<Spanned<String>>,
 }
 
-pub type InlineText = Vec<Inline>;
+/// characters that may start something else than plain text
+static SPECIAL_CHARS: &'static str = "`<[]!*_\\\n&";
+
+/// characters that may start something else than plain text with smart punctuation
+static SMART_PUNCTUATION_CHARS: &'static str = "'\"-.";
 
 
-fn find_first_uri_autolink(text: &str) -> Option<(Inline, uint, uint)> {
+fn match_uri_autolink(text: &str) -> Option<(Inline, uint)> {
     return match RX_ABSOLUTE_URI.find(text) {
-        Some((from, to)) => Some((Inline::URIAutolink(text.slice(from+1, to-1).to_string()), from, to)),
-        None => None,
+        Some((0, to)) => Some((Inline::URIAutolink(text.slice(1, to-1).to_string()), to)),
+        _ => None,
     }
 }
 
 
-fn find_first_email(text: &str) -> Option<(Inline, uint, uint)> {
+fn match_email(text: &str) -> Option<(Inline, uint)> {
     return match RX_EMAIL_ADDRESS.find(text) {
-        Some((from, to)) => Some((Inline::EmailAutolink(text.slice(from+1, to-1).to_string()), from, to)),
-        None => None,
+        Some((0, to)) => Some((Inline::EmailAutolink(text.slice(1, to-1).to_string()), to)),
+        _ => None,
     }
 }
 
 
-fn find_first_code_span(text: &str) -> Option<(Inline, uint, uint)> {
-    let mut pos = 0;
-    let mut seen_bts: Vec<(uint, uint)> = Vec::new();
-    while pos < text.len() {
-        let CharRange {ch, next} = text.char_range_at(pos);
-        if ch == '`' {
-            let bts_start = pos;
-            let mut bts_length = 1u;
-
-            // collect backtick string
-            pos = next;
-            while pos < text.len() {
-                let CharRange {ch, next} = text.char_range_at(pos);
-                pos = next;
-                if ch == '`' {
-                    bts_length += 1;
+/// an URL or `www.` link at the start of the text, without angle brackets
+///
+/// Trailing punctuation and unbalanced closing parentheses are not part of URLs, as they usually
+/// belong to the sentence around it.
+fn match_extended_url(text: &str) -> Option<(Inline, uint)> {
+    if let Some((_, to)) = RX_EXTENDED_URL.find(text) {
+        let length = trim_autolink_end(text.slice_to(to));
+        let url = text.slice_to(length);
+        let domain = RX_DOMAIN.captures(url).unwrap().at(1).unwrap();
+        if !is_valid_domain(domain) || (url.starts_with("www.") && !domain.contains_char('.')) {
+            return None;
+        }
+        return Some((Inline::URIAutolink(url.to_string()), length));
+    }
+    return None;
+}
+
+/// an email address at the start of the text, without angle brackets
+fn match_extended_email(text: &str) -> Option<(Inline, uint)> {
+    if let Some((_, to)) = RX_EXTENDED_EMAIL.find(text) {
+        let address = text.slice_to(to);
+        if address.ends_with("-") || address.ends_with("_") {
+            return None;
+        }
+        return Some((Inline::EmailAutolink(address.to_string()), to));
+    }
+    return None;
+}
+
+/// the length of an extended autolink without trailing punctuation, unbalanced closing
+/// parentheses and something that looks like an entity reference
+fn trim_autolink_end(link: &str) -> uint {
+    let mut end = link.len();
+    while end > 0 {
+        let link = link.slice_to(end);
+        match link.as_bytes()[end - 1] {
+            b'?' | b'!' | b'.' | b',' | b':' | b'*' | b'_' | b'~' => {
+                end -= 1;
+            },
+            b')' if link.chars().filter(|&c| c == ')').count() > link.chars().filter(|&c| c == '(').count() => {
+                end -= 1;
+            },
+            b';' => {
+                let name_start = link.slice_to(end - 1).trim_right_matches(|c: char| c.is_alphanumeric()).len();
+                if name_start < end - 1 && name_start > 0 && link.as_bytes()[name_start - 1] == b'&' {
+                    end = name_start - 1;
                 } else {
                     break;
                 }
-            }
+            },
+            _ => break,
+        }
+    }
+    return end;
+}
 
-            // look for backtick string of the same length in seen_bts, the text between is the
-            // code string
-            for &(seen_bts_start, seen_bts_length) in seen_bts.iter() {
-                if seen_bts_length == bts_length {
-                    return Some((Inline::CodeSpan(
-                                text.slice(seen_bts_start+bts_length, bts_start).to_string()),
-                                seen_bts_start,
-                                bts_start+bts_length));
-                }
-            }
+/// a valid domain has no underscores in its last two segments
+fn is_valid_domain(domain: &str) -> bool {
+    return !domain.split('.').rev().take(2).any(|segment| segment.contains_char('_'));
+}
 
-            // not found, so we append the position info of the backtick string to seen_bts
-            seen_bts.push((bts_start, bts_length))
-        } else {
-            pos = next;
-        }
+/// the destination of an URI autolink. `www.` links without a scheme use "http"
+pub fn autolink_destination(uri: &str) -> String {
+    if uri.starts_with("www.") {
+        return format!("http://{}", uri);
     }
-    return None;
+    return uri.to_string();
 }
 
-fn find_first_html(text: &str) -> Option<(Inline, uint, uint)> {
-    return None;
+
+fn match_html(text: &str) -> Option<(Inline, uint)> {
+    return match RX_HTML_TAG.find(text) {
+        Some((0, to)) => Some((Inline::HTMLTag(text.slice_to(to).to_string()), to)),
+        _ => None,
+    }
+}
+
+
+/// an opening "[" or "![" that may become the start of a link or image
+struct Bracket {
+    node_index: uint,       // index of the text node with the bracket in the parser's nodes
+    text_pos: uint,         // position in the text right after the bracket
+    delimiter_bottom: uint, // number of delimiters on the stack before the bracket
+    image: bool,
+    active: bool,           // false if the bracket can't start a link anymore, as links can't contain links
+}
+
+/// a run of "*" or "_" that may open or close emphasis
+struct Delimiter {
+    ch: char,
+    node_index: uint,       // index of the text node with the run in the parser's nodes
+    count: uint,            // number of characters of the run that are not used up yet
+    original_count: uint,
+    start: uint,            // position in the text of the characters that are not used up yet
+    end: uint,
+    can_open: bool,
+    can_close: bool,
 }
 
-fn parse_autolinks_code_and_html(text: &str) -> InlineText {
-    let mut result = Vec::new();
-    let mut pos_in_text = 0u;
-    loop {
-        let subslice = text.slice_from(pos_in_text);
+/// a line of the text to parse, with its position in the text, its length and the position of
+/// its end in the source
+struct Line {
+    text_pos: uint,
+    length: uint,
+    end: Position,
+}
 
-        // look for the first autolink, code or html block in the text
-        let possible_absolut_uri = find_first_uri_autolink(subslice);
-        let possible_email = find_first_email(subslice);
-        let possible_code_span = find_first_code_span(subslice);
-        let possible_html = find_first_html(subslice);
+struct InlineParser<'a> {
+    text: &'a str,
+    pos: uint,
+    nodes: InlineText,
+    brackets: Vec<Bracket>,
+    delimiters: Vec<Delimiter>,
+    refs: &'a LinkReferenceMap,
+    usage: &'a mut ReferenceUsage,
+    footnotes: &'a HashSet<String>,
+    base: uint,             // position of the text in the joined lines
+    lines: &'a [Line],
+    source_map: &'a SourceMap,
+    options: &'a ParseOptions,
+}
 
-        let mut first_element = box possible_absolut_uri;
-        if possible_email.is_some() && (first_element.is_none() || possible_email.as_ref().unwrap().1 < first_element.as_ref().unwrap().1) {
-            first_element = box possible_email;
+impl<'a> InlineParser<'a> {
+    fn new(text: &'a str, base: uint, lines: &'a [Line], refs: &'a LinkReferenceMap,
+           usage: &'a mut ReferenceUsage, footnotes: &'a HashSet<String>, source_map: &'a SourceMap,
+           options: &'a ParseOptions) -> InlineParser<'a> {
+        InlineParser {
+            text: text,
+            pos: 0,
+            nodes: Vec::new(),
+            brackets: Vec::new(),
+            delimiters: Vec::new(),
+            refs: refs,
+            usage: usage,
+            footnotes: footnotes,
+            base: base,
+            lines: lines,
+            source_map: source_map,
+            options: options
         }
-        if possible_code_span.is_some() && (first_element.is_none() || possible_code_span.as_ref().unwrap().1 < first_element.as_ref().unwrap().1) {
-            first_element = box possible_code_span;
+    }
+
+    fn parse(mut self) -> InlineText {
+        while self.pos < self.text.len() {
+            match self.text.char_at(self.pos) {
+                '`' => self.parse_code_span(),
+                '<' => self.parse_autolink_or_html(),
+                '[' => self.open_bracket(false),
+                '!' if self.text.slice_from(self.pos).starts_with("![") => self.open_bracket(true),
+                ']' => self.close_bracket(),
+                '*' | '_' => self.parse_delimiter_run(),
+                '~' if self.options.strikethrough => self.parse_delimiter_run(),
+                '\\' => self.parse_backslash(),
+                '\n' => self.parse_line_break(),
+                '&' => self.parse_entity(),
+                '\'' | '"' if self.options.smart_punctuation => self.parse_quote(),
+                '-' if self.options.smart_punctuation => self.parse_dashes(),
+                '.' if self.options.smart_punctuation => self.parse_ellipsis(),
+                _ => self.parse_text(),
+            }
         }
-        if possible_html.is_some() && (first_element.is_none() || possible_html.as_ref().unwrap().1 < first_element.as_ref().unwrap().1) {
-            first_element = box possible_html;
+        self.process_emphasis(0);
+        return merge_text_nodes(self.nodes);
+    }
+
+    /// the position in the source of a position in the text
+    fn position(&self, text_pos: uint) -> Position {
+        let text_pos = self.base + text_pos;
+        let line = match self.lines.iter().rev().find(|line| line.text_pos <= text_pos) {
+            Some(line) => line,
+            None => return Position { line: 1, column: 1, offset: 0 },
+        };
+        let distance = text_pos - line.text_pos;
+        let before_end = if distance > line.length { 0 } else { line.length - distance };
+        return self.source_map.position_before(line.end, before_end);
+    }
+
+    fn push_node(&mut self, inline: Inline, start: uint, end: uint) {
+        let span = Span { start: self.position(start), end: self.position(end) };
+        self.nodes.push(Spanned { node: inline, span: span });
+    }
+
+    /// adds a text node for the characters from `start` to `end` of the text
+    fn push_text(&mut self, text: &str, start: uint, end: uint) {
+        self.push_node(Inline::Text(text.to_string()), start, end);
+    }
+
+    /// whether a character may start something else than plain text
+    fn is_special(&self, ch: char) -> bool {
+        return SPECIAL_CHARS.contains_char(ch) ||
+            (self.options.smart_punctuation && SMART_PUNCTUATION_CHARS.contains_char(ch)) ||
+            (self.options.strikethrough && ch == '~');
+    }
+
+    /// consumes at least one character, and everything up to the next special character or
+    /// extended autolink
+    fn parse_text(&mut self) {
+        let text = self.text;
+        let start = self.pos;
+        loop {
+            let mut found = None;
+            if self.options.autolinks && !self.in_link_text() {
+                if self.at_autolink_boundary() {
+                    found = match_extended_url(text.slice_from(self.pos));
+                }
+                if found.is_none() && self.at_local_part_start() {
+                    found = match_extended_email(text.slice_from(self.pos));
+                }
+            }
+            if let Some((inline, length)) = found {
+                if self.pos > start {
+                    self.push_text(text.slice(start, self.pos), start, self.pos);
+                }
+                let link_start = self.pos;
+                self.pos += length;
+                self.push_node(inline, link_start, self.pos);
+                return;
+            }
+            self.pos = text.char_range_at(self.pos).next;
+            if self.pos >= text.len() || self.is_special(text.char_at(self.pos)) {
+                break;
+            }
         }
+        self.push_text(text.slice(start, self.pos), start, self.pos);
+    }
 
-        // if there is a autolink, code or html element, split the text and process the second half
-        // TODO: there is room for optimization: don't throw away found but unused elements
-        if first_element.is_none() {
-            break;
-        } else {
-            let (inline, start, end) = first_element.unwrap();
-            result.push(Inline::Text(subslice.slice_to(start).to_string()));
-            result.push(inline);
-            pos_in_text += end;
+    /// whether the current position is inside of the text of a link, where extended autolinks
+    /// aren't recognized
+    fn in_link_text(&self) -> bool {
+        return self.brackets.iter().any(|bracket| bracket.active && !bracket.image);
+    }
+
+    /// whether an extended URL autolink may start at the current position, which is at the
+    /// start of the text or after whitespace, "*", "_", "~" or "("
+    fn at_autolink_boundary(&self) -> bool {
+        if self.pos == 0 {
+            return true;
         }
+        let before = self.text.char_range_at_reverse(self.pos).ch;
+        return before.is_whitespace() || "*_~(".contains_char(before);
     }
 
-    // don't forget the rest of the string
-    result.push(Inline::Text(text.slice_from(pos_in_text).to_string()));
-    return result;
-}
+    /// whether the local part of an email address may start at the current position
+    ///
+    /// Like the "@" scanning back over the characters of the local part, the local part starts
+    /// after any character that can't be part of it, such as quotes or a ":".
+    fn at_local_part_start(&self) -> bool {
+        if self.pos == 0 {
+            return true;
+        }
+        let before = self.text.char_range_at_reverse(self.pos).ch;
+        return !((before < '\u{80}' && before.is_alphanumeric()) || ".+_-".contains_char(before));
+    }
 
-struct Emphasis {
-    ch: char,
-    pos: uint,
-    length: uint,
-}
+    /// a backslash escapes ASCII punctuation, or is a hard line break at the end of a line
+    fn parse_backslash(&mut self) {
+        let text = self.text;
+        let start = self.pos;
+        self.pos += 1;
+        if self.pos < text.len() {
+            let next = text.as_bytes()[self.pos];
+            if next == b'\n' {
+                self.pos += 1;
+                self.push_node(Inline::HardBreak, start, self.pos);
+                self.skip_spaces();
+                return;
+            }
+            if is_ascii_punctuation(next) {
+                self.pos += 1;
+                self.push_text(text.slice(self.pos - 1, self.pos), start, self.pos);
+                return;
+            }
+        }
+        self.push_text("\\", start, self.pos);
+    }
 
-fn parse_emphasis_and_strong(s: &str) -> InlineText {
-    let mut result: InlineText = Vec::new();
-    let mut positions: Vec<(uint, uint, uint)> = Vec::new();
-    let mut stack: Vec<Emphasis> = Vec::new();
-    let mut pos = 0;
+    fn parse_entity(&mut self) {
+        let text = self.text;
+        let start = self.pos;
+        match match_entity(text.slice_from(start)) {
+            Some((characters, length)) => {
+                self.pos += length;
+                self.push_node(Inline::Text(characters), start, self.pos);
+            },
+            None => {
+                self.pos += 1;
+                self.push_text("&", start, self.pos);
+            }
+        }
+    }
 
-    // go through all characters from left to right and look for * or _
-    while pos < s.len() {
-        let CharRange {ch, next} = s.char_range_at(pos);
+    /// a line break is hard if it is preceded by at least two spaces. Spaces at the end and the
+    /// beginning of lines are removed
+    fn parse_line_break(&mut self) {
+        let line_end = self.pos;
+        self.pos += 1;
+        let trailing_spaces = match self.nodes.last() {
+            Some(&Spanned { node: Inline::Text(ref previous), .. }) => {
+                previous.len() - previous.as_slice().trim_right_matches(' ').len()
+            },
+            _ => 0,
+        };
+        let start = line_end - trailing_spaces;
+        if trailing_spaces > 0 {
+            let trimmed_end = self.position(start);
+            if let Some(&mut Spanned { node: Inline::Text(ref mut previous), ref mut span }) = self.nodes.last_mut() {
+                let trimmed_length = previous.len() - trailing_spaces;
+                previous.truncate(trimmed_length);
+                span.end = trimmed_end;
+            }
+        }
+        let line_break = if trailing_spaces >= 2 { Inline::HardBreak } else { Inline::SoftBreak };
+        let end = self.pos;
+        self.push_node(line_break, start, end);
+        self.skip_spaces();
+    }
 
-        if ch == '*' || ch == '_' {
+    fn skip_spaces(&mut self) {
+        while self.pos < self.text.len() && self.text.as_bytes()[self.pos] == b' ' {
+            self.pos += 1;
+        }
+    }
 
-            // collect the whole string of *** or ___
-            let symbol = ch;
-            let symbol_start = pos;
-            let mut length = 1u;
-            let mut space_before: bool;
-            let mut space_after: bool;
-            let mut ascii_alphanum_before: bool;
-            let mut ascii_alphanum_after: bool;
+    fn parse_code_span(&mut self) {
+        let text = self.text;
+        let start = self.pos;
+        let opening_length = count_run(text, start, b'`');
+        let content_start = start + opening_length;
 
-            if pos == 0 {
-                space_before = true;
-                ascii_alphanum_before = false;
+        // look for a backtick string of the same length
+        let mut pos = content_start;
+        while pos < text.len() {
+            if text.as_bytes()[pos] == b'`' {
+                let length = count_run(text, pos, b'`');
+                if length == opening_length {
+                    let code = normalize_code_span(text.slice(content_start, pos));
+                    self.pos = pos + length;
+                    self.push_node(Inline::CodeSpan(code), start, self.pos);
+                    return;
+                }
+                pos += length;
             } else {
-                let CharRange {ch, next} = s.char_range_at_reverse(pos);
-                space_before = ch == ' ';
-                ascii_alphanum_before = ch.is_alphanumeric(); //XXX consider only ASCII
+                pos += 1;
             }
+        }
 
-            let mut symbol_pos = next;
-            while symbol_pos < s.len() {
-                let CharRange {ch, next} = s.char_range_at(symbol_pos);
-                if ch == symbol {
-                    length += 1;
-                    symbol_pos = next;
+        // not found, so the backticks are just text
+        self.push_text(text.slice(start, content_start), start, content_start);
+        self.pos = content_start;
+    }
+
+    fn parse_autolink_or_html(&mut self) {
+        let text = self.text;
+        let start = self.pos;
+        let rest = text.slice_from(start);
+        let found = match_uri_autolink(rest)
+            .or_else(|| match_email(rest))
+            .or_else(|| match_html(rest));
+        match found {
+            Some((inline, length)) => {
+                self.pos += length;
+                self.push_node(inline, start, self.pos);
+            },
+            None => {
+                self.pos += 1;
+                self.push_text("<", start, self.pos);
+            }
+        }
+    }
+
+    fn open_bracket(&mut self, image: bool) {
+        let text = self.text;
+        let start = self.pos;
+        self.pos += if image { 2 } else { 1 };
+        self.brackets.push(Bracket {
+            node_index: self.nodes.len(),
+            text_pos: self.pos,
+            delimiter_bottom: self.delimiters.len(),
+            image: image,
+            active: true
+        });
+        let end = self.pos;
+        self.push_text(text.slice(start, end), start, end);
+    }
+
+    fn close_bracket(&mut self) {
+        let closer_pos = self.pos;
+        self.pos += 1;
+
+        let opener = match self.brackets.pop() {
+            Some(opener) => opener,
+            None => {
+                self.push_text("]", closer_pos, closer_pos + 1);
+                return;
+            }
+        };
+
+        if !opener.active {
+            self.push_text("]", closer_pos, closer_pos + 1);
+            return;
+        }
+
+        match self.parse_link_target(opener.text_pos, closer_pos) {
+            None if !opener.image && self.is_footnote_reference(opener.text_pos, closer_pos) => {
+                // the label is replaced by the reference, with the delimiters in it
+                self.delimiters.truncate(opener.delimiter_bottom);
+                self.nodes.truncate(opener.node_index);
+                let label = self.text.slice(opener.text_pos + 1, closer_pos).to_string();
+                self.push_node(Inline::FootnoteReference(label, 0), opener.text_pos - 1, self.pos);
+            },
+            None => {
+                self.push_text("]", closer_pos, closer_pos + 1);
+            },
+            Some((destination, title)) => {
+                self.process_emphasis(opener.delimiter_bottom);
+
+                // everything after the opening bracket is the link text
+                let mut content: InlineText = Vec::new();
+                while self.nodes.len() > opener.node_index + 1 {
+                    content.push(self.nodes.pop().unwrap());
+                }
+                content.reverse();
+                self.nodes.pop();
+
+                let content = merge_text_nodes(content);
+                let start = opener.text_pos - if opener.image { 2 } else { 1 };
+                let end = self.pos;
+                if opener.image {
+                    self.push_node(Inline::Image(content, destination, title), start, end);
                 } else {
-                    break;
+                    self.push_node(Inline::Link(content, destination, title), start, end);
+                    for bracket in self.brackets.iter_mut() {
+                        if !bracket.image {
+                            bracket.active = false;
+                        }
+                    }
                 }
             }
-            let after_symbols = symbol_pos;
+        }
+    }
 
-            if after_symbols >= s.len() {
-                space_after = true;
-                ascii_alphanum_after = false;
-            } else {
-                space_after = s.char_at(after_symbols) == ' ';
-                ascii_alphanum_after = s.char_at(after_symbols).is_alphanumeric(); //XXX
-            }
+    /// whether the bracketed text is "^" followed by the label of a footnote definition. The
+    /// number is assigned once the whole document is parsed
+    fn is_footnote_reference(&self, text_pos: uint, closer_pos: uint) -> bool {
+        let text = self.text.slice(text_pos, closer_pos);
+        return self.options.footnotes && text.starts_with("^") && !text.chars().any(|c| c.is_whitespace()) &&
+            self.footnotes.contains(&normalize_label(text.slice_from(1)));
+    }
 
-            pos = after_symbols;
+    fn parse_delimiter_run(&mut self) {
+        let text = self.text;
+        let start = self.pos;
+        let ch = text.char_at(start);
+        let count = count_run(text, start, ch as u8);
+        self.pos += count;
 
+        let (before, after) = self.surrounding_chars(start, self.pos);
+        let (left_flanking, right_flanking) = flanking(before, after);
 
-            let potential_begin = Emphasis{ch:symbol, pos:symbol_start, length: length};
+        // "_" can't open or close emphasis inside of words
+        let (can_open, can_close) = if ch == '_' {
+            (left_flanking && (!right_flanking || is_punctuation(before)),
+             right_flanking && (!left_flanking || is_punctuation(after)))
+        } else if ch == '~' && count > 2 {
+            // strikethrough uses one or two tildes, longer runs are just text
+            (false, false)
+        } else {
+            (left_flanking, right_flanking)
+        };
 
-            // put sequences of * or _ that definitively start an emph/strong part on the stack
-            if !space_after && (symbol == '*' && space_before) ||
-                        (symbol == '_' && !ascii_alphanum_before) {
-                stack.push(potential_begin);
+        if can_open || can_close {
+            self.delimiters.push(Delimiter {
+                ch: ch,
+                node_index: self.nodes.len(),
+                count: count,
+                original_count: count,
+                start: start,
+                end: self.pos,
+                can_open: can_open,
+                can_close: can_close
+            });
+        }
+        let end = self.pos;
+        self.push_text(text.slice(start, end), start, end);
+    }
+
+    /// the characters before and after a part of the text. The beginning and end of the text
+    /// count as whitespace
+    fn surrounding_chars(&self, start: uint, end: uint) -> (char, char) {
+        let text = self.text;
+        let before = if start == 0 { ' ' } else { text.char_range_at_reverse(start).ch };
+        let after = if end >= text.len() { ' ' } else { text.char_at(end) };
+        return (before, after);
+    }
+
+    /// a straight quote becomes a curly quote. It is a right quote, unless it opens a quotation
+    /// that is closed later
+    fn parse_quote(&mut self) {
+        let start = self.pos;
+        let ch = self.text.char_at(start);
+        self.pos += 1;
+
+        let (before, after) = self.surrounding_chars(start, self.pos);
+        let (left_flanking, right_flanking) = flanking(before, after);
+        let can_open = left_flanking && !right_flanking && before != ']' && before != ')';
+        let can_close = right_flanking;
+
+        if can_open || can_close {
+            self.delimiters.push(Delimiter {
+                ch: ch,
+                node_index: self.nodes.len(),
+                count: 1,
+                original_count: 1,
+                start: start,
+                end: self.pos,
+                can_open: can_open,
+                can_close: can_close
+            });
+        }
+        let quote = match ch {
+            '\'' => "\u{2019}",
+            _ if can_close => "\u{201D}",
+            _ => "\u{201C}",
+        };
+        let end = self.pos;
+        self.push_text(quote, start, end);
+    }
+
+    /// "--" becomes an en dash and "---" an em dash. Longer runs of hyphens are split into em
+    /// and en dashes, with as few en dashes as possible
+    fn parse_dashes(&mut self) {
+        let start = self.pos;
+        let count = count_run(self.text, start, b'-');
+        self.pos += count;
+
+        let (em_dashes, en_dashes) = if count == 1 {
+            (0, 0)
+        } else if count % 3 == 0 {
+            (count / 3, 0)
+        } else if count % 2 == 0 {
+            (0, count / 2)
+        } else if count % 3 == 2 {
+            ((count - 2) / 3, 1)
+        } else {
+            ((count - 4) / 3, 2)
+        };
+        let mut dashes: String = repeat('\u{2014}').take(em_dashes).collect();
+        dashes.extend(repeat('\u{2013}').take(en_dashes));
+        if count == 1 {
+            dashes.push('-');
+        }
+        let end = self.pos;
+        self.push_text(dashes.as_slice(), start, end);
+    }
+
+    fn parse_ellipsis(&mut self) {
+        let start = self.pos;
+        if self.text.slice_from(start).starts_with("...") {
+            self.pos += 3;
+            self.push_text("\u{2026}", start, start + 3);
+        } else {
+            self.pos += 1;
+            self.push_text(".", start, start + 1);
+        }
+    }
+
+    /// turns matching delimiters on the stack above `bottom` into emphasis and strong emphasis,
+    /// and removes them from the stack
+    fn process_emphasis(&mut self, bottom: uint) {
+        let mut closer = bottom;
+        while closer < self.delimiters.len() {
+            if !self.delimiters[closer].can_close {
+                closer += 1;
                 continue;
             }
-
-            // a sequence of * inside text (e.g. bla**bla) ends a emph/strong part if there is
-            // a corresponding start on the stack. Otherwise, it starts an e/s part
-            if !space_after && symbol == '*' && !space_before {
-                match stack.iter().rev().position(|e| e.ch == potential_begin.ch) {
-                    Some(pos_in_reverse_stack) => {
-                        let starting_element_pos = stack.len() - 1 - pos_in_reverse_stack;
-                        positions.push(make_emph_strong_tag(&mut stack, starting_element_pos, potential_begin));
-                    },
-                    None => {
-                        stack.push(potential_begin);
+            match self.find_opener(bottom, closer) {
+                None => {
+                    closer += 1;
+                },
+                Some(opener) if is_quote(self.delimiters[closer].ch) => {
+                    self.match_quotes(opener, closer);
+                    closer -= 1;
+                },
+                Some(opener) => {
+                    // the delimiters in between are inside the emphasis and can't match anymore
+                    for _ in range(opener + 1, closer) {
+                        self.delimiters.remove(opener + 1);
                     }
+                    let opener_removed = self.make_emphasis(opener, opener + 1);
+                    // the closer might have characters left for another match, otherwise this is
+                    // the next delimiter
+                    closer = if opener_removed { opener } else { opener + 1 };
                 }
-                continue;
             }
+        }
+        self.delimiters.truncate(bottom);
+    }
 
-            // sequences of * or _ that end an e/s part
-            if !space_before && (symbol == '*' || (symbol == '_' && !ascii_alphanum_after)) {
-                if let Some(pos_in_reverse_stack) = stack.iter().rev().position(|e| e.ch == potential_begin.ch) {
-                    let starting_element_pos = stack.len() - 1 - pos_in_reverse_stack;
-                    positions.push(make_emph_strong_tag(&mut stack, starting_element_pos, potential_begin));
-                }
+    /// makes a pair of matching quotes a left and a right quote, and removes them from the stack
+    fn match_quotes(&mut self, opener: uint, closer: uint) {
+        let (left, right) = if self.delimiters[opener].ch == '"' {
+            ("\u{201C}", "\u{201D}")
+        } else {
+            ("\u{2018}", "\u{2019}")
+        };
+        let opener_node = self.delimiters[opener].node_index;
+        let closer_node = self.delimiters[closer].node_index;
+        self.nodes[opener_node].node = Inline::Text(left.to_string());
+        self.nodes[closer_node].node = Inline::Text(right.to_string());
+        self.delimiters.remove(closer);
+        self.delimiters.remove(opener);
+    }
+
+    fn find_opener(&self, bottom: uint, closer_index: uint) -> Option<uint> {
+        let closer = &self.delimiters[closer_index];
+        let mut i = closer_index;
+        while i > bottom {
+            i -= 1;
+            let opener = &self.delimiters[i];
+            if opener.ch != closer.ch || !opener.can_open {
+                continue;
+            }
+            // strikethrough needs the same number of tildes on both sides
+            if opener.ch == '~' && opener.count != closer.count {
+                continue;
+            }
+            // "rule of 3": if one of the delimiters can both open and close, the sum of their
+            // lengths must not be a multiple of 3, unless both lengths are
+            if (opener.can_close || closer.can_open) &&
+                    (opener.original_count + closer.original_count) % 3 == 0 &&
+                    !(opener.original_count % 3 == 0 && closer.original_count % 3 == 0) {
+                continue;
             }
+            return Some(i);
+        }
+        return None;
+    }
 
+    /// wraps the nodes between two adjacent delimiters on the stack in an emphasis or
+    /// strikethrough node, and uses up one or two characters of both delimiters
+    ///
+    /// The innermost characters are used, so the emphasis starts at the end of the opener and
+    /// ends at the start of the closer. Delimiters that are used up completely are removed.
+    /// Returns whether the opener was removed.
+    fn make_emphasis(&mut self, opener: uint, closer: uint) -> bool {
+        let used = if self.delimiters[opener].count >= 2 && self.delimiters[closer].count >= 2 { 2 } else { 1 };
+        let opener_node = self.delimiters[opener].node_index;
+        let closer_node = self.delimiters[closer].node_index;
+        let span = Span {
+            start: self.position(self.delimiters[opener].end - used),
+            end: self.position(self.delimiters[closer].start + used),
+        };
+
+        let mut content: InlineText = Vec::new();
+        for _ in range(opener_node + 1, closer_node) {
+            content.push(self.nodes.remove(opener_node + 1));
+        }
+        let content = merge_text_nodes(content);
+        let emphasis = if self.delimiters[opener].ch == '~' {
+            Inline::Strikethrough(content)
+        } else if used == 2 {
+            Inline::Strong(content)
         } else {
-            pos = next;
+            Inline::Emph(content)
+        };
+        self.nodes.insert(opener_node + 1, Spanned { node: emphasis, span: span });
+        let moved_nodes = closer_node - opener_node - 1;
+        for delimiter in self.delimiters.slice_from_mut(closer).iter_mut() {
+            delimiter.node_index = delimiter.node_index + 1 - moved_nodes;
         }
+
+        self.use_delimiter_chars(closer, used, false);
+        self.use_delimiter_chars(opener, used, true);
+        if self.delimiters[closer].count == 0 {
+            self.remove_delimiter(closer);
+        }
+        if self.delimiters[opener].count == 0 {
+            self.remove_delimiter(opener);
+            return true;
+        }
+        return false;
     }
-    
 
-    let mut p = 0;
-    for &(emph_start, emph_end, emph_length) in positions.iter() {
-        if emph_start > 0 {
-            result.push(Inline::Text(s.slice(p, emph_start).to_string()));
+    /// uses up characters at the end of an opener or at the start of a closer
+    fn use_delimiter_chars(&mut self, delimiter: uint, count: uint, opener: bool) {
+        {
+            let run = &mut self.delimiters[delimiter];
+            run.count -= count;
+            if opener {
+                run.end -= count;
+            } else {
+                run.start += count;
+            }
         }
-        result.push(make_emph_strong_inline(s.slice(emph_start + emph_length, emph_end).to_string(), emph_length));
-        p = emph_end + emph_length;
+        let remaining = self.delimiters[delimiter].count;
+        let node_index = self.delimiters[delimiter].node_index;
+        let span = Span {
+            start: self.position(self.delimiters[delimiter].start),
+            end: self.position(self.delimiters[delimiter].end),
+        };
+        let node = &mut self.nodes[node_index];
+        if let Inline::Text(ref mut text) = node.node {
+            text.truncate(remaining);
+        }
+        node.span = span;
     }
-    if p < s.len()-1 {
-        result.push(Inline::Text(s.slice_from(p).to_string()));
+
+    /// removes a delimiter together with its text node
+    fn remove_delimiter(&mut self, delimiter: uint) {
+        let removed = self.delimiters.remove(delimiter);
+        self.nodes.remove(removed.node_index);
+        for delimiter in self.delimiters.slice_from_mut(delimiter).iter_mut() {
+            delimiter.node_index -= 1;
+        }
+    }
+
+    /// looks for the destination and title of a link whose text ends at `closer_pos`
+    ///
+    /// The current position is right after the closing bracket, and is moved behind the
+    /// link target if one is found.
+    fn parse_link_target(&mut self, text_pos: uint, closer_pos: uint) -> Option<(String, String)> {
+        let text = self.text;
+
+        // inline link [text](destination "title")
+        if text.slice_from(self.pos).starts_with("(") {
+            if let Some((destination, title, end)) = parse_inline_link_target(text, self.pos) {
+                self.pos = end;
+                return Some((destination, title));
+            }
+        }
+
+        // full reference link [text][label], collapsed reference link [text][], or shortcut
+        // reference link [text]
+        let (label, shortcut) = match parse_link_label(text, self.pos) {
+            Some((label, end)) => {
+                self.pos = end;
+                if label.is_empty() {
+                    (text.slice(text_pos, closer_pos), false)
+                } else {
+                    (label, false)
+                }
+            },
+            None => (text.slice(text_pos, closer_pos), true)
+        };
+
+        let normalized_label = normalize_label(label);
+        return match self.refs.get(&normalized_label) {
+            Some(&(ref destination, ref title)) => {
+                self.usage.used_labels.insert(normalized_label);
+                Some((destination.clone(), title.clone()))
+            },
+            None => {
+                // text in brackets is only a mistake if a label follows
+                if !shortcut {
+                    let span = Span { start: self.position(text_pos - 1), end: self.position(self.pos) };
+                    self.usage.undefined.push(Spanned { node: label.to_string(), span: span });
+                }
+                self.pos = closer_pos + 1;
+                None
+            }
+        }
+    }
+}
+
+
+/// joins adjacent text nodes
+fn merge_text_nodes(nodes: InlineText) -> InlineText {
+    let mut result: InlineText = Vec::new();
+    for node in nodes.into_iter() {
+        if let Inline::Text(ref text) = node.node {
+            if let Some(&mut Spanned { node: Inline::Text(ref mut previous), ref mut span }) = result.last_mut() {
+                previous.push_str(text.as_slice());
+                span.end = node.span.end;
+                continue;
+            }
+        }
+        result.push(node);
     }
     return result;
 }
 
-fn make_emph_strong_inline(text: String, number_of_emphs: uint) -> Inline {
-    let mut n = number_of_emphs;
-    let mut result: Inline = Inline::Text(text);
-    if n % 2 == 1 {
-        result = Inline::Emph(box result);
-        n -= 1;
+
+/// whether a delimiter run is left-flanking and right-flanking, from the characters before and
+/// after it
+pub fn flanking(before: char, after: char) -> (bool, bool) {
+    let left_flanking = !after.is_whitespace() &&
+        (!is_punctuation(after) || before.is_whitespace() || is_punctuation(before));
+    let right_flanking = !before.is_whitespace() &&
+        (!is_punctuation(before) || after.is_whitespace() || is_punctuation(after));
+    return (left_flanking, right_flanking);
+}
+
+fn is_quote(ch: char) -> bool {
+    return ch == '\'' || ch == '"';
+}
+
+
+/// the number of times `ch` is repeated, starting at `pos`
+fn count_run(text: &str, pos: uint, ch: u8) -> uint {
+    let mut length = 0u;
+    while pos + length < text.len() && text.as_bytes()[pos + length] == ch {
+        length += 1;
+    }
+    return length;
+}
+
+
+/// line endings become spaces, and one leading and trailing space is stripped
+fn normalize_code_span(code: &str) -> String {
+    let code = code.replace("\n", " ");
+    if code.len() >= 2 && code.starts_with(" ") && code.ends_with(" ") &&
+            !code.as_slice().chars().all(|c| c == ' ') {
+        return code.as_slice().slice(1, code.len() - 1).to_string();
+    }
+    return code;
+}
+
+
+fn is_ascii_punctuation(b: u8) -> bool {
+    return b"!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~".contains(&b);
+}
+
+
+/// ASCII punctuation and the Unicode punctuation characters (categories Pc, Pd, Pe, Pf, Pi, Po
+/// and Ps) of the Latin-1, General Punctuation, Supplemental Punctuation, CJK and fullwidth
+/// blocks
+pub fn is_punctuation(ch: char) -> bool {
+    if (ch as u32) < 128 {
+        return is_ascii_punctuation(ch as u8);
+    }
+    return match ch {
+        '\u{A1}' | '\u{A7}' | '\u{AB}' | '\u{B6}' | '\u{B7}' | '\u{BB}' | '\u{BF}' => true,
+        '\u{2010}'...'\u{2027}' | '\u{2030}'...'\u{2043}' | '\u{2045}'...'\u{2051}' => true,
+        '\u{2053}'...'\u{205E}' | '\u{2E00}'...'\u{2E4F}' => true,
+        '\u{3001}'...'\u{3003}' | '\u{3008}'...'\u{3011}' | '\u{3014}'...'\u{301F}' => true,
+        '\u{FF01}'...'\u{FF03}' | '\u{FF05}'...'\u{FF0A}' | '\u{FF0C}'...'\u{FF0F}' => true,
+        '\u{FF1A}' | '\u{FF1B}' | '\u{FF1F}' | '\u{FF20}' | '\u{FF3B}'...'\u{FF3D}' => true,
+        '\u{FF3F}' | '\u{FF5B}' | '\u{FF5D}' | '\u{FF5F}'...'\u{FF65}' => true,
+        _ => false,
+    }
+}
+
+
+/// decodes an entity or numeric character reference at the start of `text`
+///
+/// Returns the characters and the length of the reference. Invalid code points are replaced by
+/// U+FFFD.
+fn match_entity(text: &str) -> Option<(String, uint)> {
+    let cap = match RX_ENTITY.captures(text) {
+        Some(cap) => cap,
+        None => return None,
+    };
+    let length = cap.at(0).unwrap().len();
+    if let Some(hex) = cap.at(1) {
+        return Some((decode_code_point(hex, 16).to_string(), length));
+    }
+    if let Some(decimal) = cap.at(2) {
+        return Some((decode_code_point(decimal, 10).to_string(), length));
     }
-    for _ in range(0, n/2) {
-        result = Inline::Strong(box result);
+    return match entities::lookup(cap.at(3).unwrap()) {
+        Some(characters) => Some((characters.to_string(), length)),
+        None => None,
+    }
+}
+
+fn decode_code_point(digits: &str, radix: uint) -> char {
+    let mut code_point = 0u32;
+    for digit in digits.chars() {
+        code_point = code_point * (radix as u32) + (digit.to_digit(radix).unwrap() as u32);
+    }
+    if code_point == 0 {
+        return '\u{FFFD}';
+    }
+    return char::from_u32(code_point).unwrap_or('\u{FFFD}');
+}
+
+
+/// replaces backslash escaped ASCII punctuation characters with the characters themselves, and
+/// decodes entity and numeric character references
+pub fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = String::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'\\' && pos + 1 < bytes.len() && is_ascii_punctuation(bytes[pos + 1]) {
+            result.push_str(s.slice(start, pos));
+            start = pos + 1;
+            pos += 2;
+        } else if bytes[pos] == b'&' {
+            match match_entity(s.slice_from(pos)) {
+                Some((characters, length)) => {
+                    result.push_str(s.slice(start, pos));
+                    result.push_str(characters.as_slice());
+                    pos += length;
+                    start = pos;
+                },
+                None => {
+                    pos += 1;
+                }
+            }
+        } else {
+            pos += 1;
+        }
+    }
+    result.push_str(s.slice_from(start));
+    return result;
+}
+
+
+/// normalizes a link label for matching it against other labels
+///
+/// Surrounding whitespace is stripped, inner whitespace is collapsed to a single space, and the
+/// label is case folded. Unicode case folding is approximated by lowercasing, plus the sharp s
+/// which folds to "ss".
+pub fn normalize_label(label: &str) -> String {
+    let mut result = String::new();
+    for word in label.words() {
+        if !result.is_empty() {
+            result.push(' ');
+        }
+        for ch in word.chars() {
+            match ch {
+                'ß' | 'ẞ' => result.push_str("ss"),
+                _ => result.push(ch.to_lowercase()),
+            }
+        }
     }
     return result;
 }
 
 
-fn make_emph_strong_tag(stack: &mut Vec<Emphasis>, pos_in_stack: uint, end_emph: Emphasis) -> (uint, uint, uint) {
-    let start_emph: Emphasis = stack.remove(pos_in_stack);
-    stack.truncate(pos_in_stack);
-    let mut start_pos: uint;
-    let mut end_pos: uint;
-    let mut length: uint;
-    if start_emph.length < end_emph.length {
-        length = start_emph.length;
-        let new_start_emph = Emphasis{ch:end_emph.ch, pos: end_emph.pos + length, length: end_emph.length - length};
-        stack.push(new_start_emph);
-        start_pos = start_emph.pos;
-        end_pos = end_emph.pos;
-    } else if start_emph.length > end_emph.length {
-        length = end_emph.length;
-        let new_start_emph = Emphasis{ch: start_emph.ch, length: start_emph.length - length, pos: start_emph.pos };
-        stack.push(new_start_emph);
-        start_pos = start_emph.pos + start_emph.length - length;
-        end_pos = end_emph.pos;
+/// parses a link label starting with the "[" at `pos`
+///
+/// Returns the text between the brackets and the position after the closing bracket.
+pub fn parse_link_label(text: &str, pos: uint) -> Option<(&str, uint)> {
+    let bytes = text.as_bytes();
+    if pos >= bytes.len() || bytes[pos] != b'[' {
+        return None;
+    }
+    let mut p = pos + 1;
+    while p < bytes.len() && p - pos <= 1000 {
+        match bytes[p] {
+            b'\\' if p + 1 < bytes.len() && is_ascii_punctuation(bytes[p + 1]) => p += 2,
+            b'[' => return None,
+            b']' => return Some((text.slice(pos + 1, p), p + 1)),
+            _ => p += 1,
+        }
+    }
+    return None;
+}
+
+
+/// parses a link destination starting at `pos`, either in angle brackets or without spaces
+/// and with balanced parentheses
+///
+/// Returns the unescaped destination and the position after it.
+pub fn parse_link_destination(text: &str, pos: uint) -> Option<(String, uint)> {
+    let bytes = text.as_bytes();
+    if pos < bytes.len() && bytes[pos] == b'<' {
+        let mut p = pos + 1;
+        while p < bytes.len() {
+            match bytes[p] {
+                b'\\' if p + 1 < bytes.len() && is_ascii_punctuation(bytes[p + 1]) => p += 2,
+                b'>' => return Some((unescape(text.slice(pos + 1, p)), p + 1)),
+                b'<' | b'\n' => return None,
+                _ => p += 1,
+            }
+        }
+        return None;
+    }
+
+    let mut depth = 0u;
+    let mut p = pos;
+    while p < bytes.len() {
+        match bytes[p] {
+            b'\\' if p + 1 < bytes.len() && is_ascii_punctuation(bytes[p + 1]) => p += 2,
+            b'(' => {
+                depth += 1;
+                p += 1;
+            },
+            b')' => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+                p += 1;
+            },
+            b if b <= b' ' || b == 0x7f => break,
+            _ => p += 1,
+        }
+    }
+    if p == pos || depth > 0 {
+        return None;
+    }
+    return Some((unescape(text.slice(pos, p)), p));
+}
+
+
+/// parses a link title in double quotes, single quotes or parentheses, starting at `pos`
+///
+/// Returns the unescaped title and the position after it.
+pub fn parse_link_title(text: &str, pos: uint) -> Option<(String, uint)> {
+    let bytes = text.as_bytes();
+    if pos >= bytes.len() {
+        return None;
+    }
+    let closer = match bytes[pos] {
+        b'"' => b'"',
+        b'\'' => b'\'',
+        b'(' => b')',
+        _ => return None,
+    };
+    let mut p = pos + 1;
+    while p < bytes.len() {
+        match bytes[p] {
+            b'\\' if p + 1 < bytes.len() && is_ascii_punctuation(bytes[p + 1]) => p += 2,
+            b if b == closer => return Some((unescape(text.slice(pos + 1, p)), p + 1)),
+            b'(' if closer == b')' => return None,
+            _ => p += 1,
+        }
+    }
+    return None;
+}
+
+
+/// skips spaces, tabs and line breaks, and also returns the number of skipped line breaks
+fn skip_whitespace(text: &str, pos: uint) -> (uint, uint) {
+    let bytes = text.as_bytes();
+    let mut p = pos;
+    let mut line_breaks = 0u;
+    while p < bytes.len() && (bytes[p] == b' ' || bytes[p] == b'\t' || bytes[p] == b'\n') {
+        if bytes[p] == b'\n' {
+            line_breaks += 1;
+        }
+        p += 1;
+    }
+    return (p, line_breaks);
+}
+
+
+/// the position after the line break, if there are only spaces between `pos` and it
+fn end_of_line(text: &str, pos: uint) -> Option<uint> {
+    let bytes = text.as_bytes();
+    let mut p = pos;
+    while p < bytes.len() && (bytes[p] == b' ' || bytes[p] == b'\t') {
+        p += 1;
+    }
+    if p == bytes.len() {
+        return Some(p);
+    }
+    return if bytes[p] == b'\n' { Some(p + 1) } else { None };
+}
+
+
+/// parses the destination and title of an inline link in parentheses, starting at the "("
+///
+/// Both destination and title may be omitted. Returns them and the position after the ")".
+fn parse_inline_link_target(text: &str, pos: uint) -> Option<(String, String, uint)> {
+    let (destination_start, line_breaks) = skip_whitespace(text, pos + 1);
+    if line_breaks > 1 {
+        return None;
+    }
+    let (destination, after_destination) = match parse_link_destination(text, destination_start) {
+        Some(result) => result,
+        None => ("".to_string(), destination_start),
+    };
+
+    // the title has to be separated from the destination by whitespace
+    let (title_start, line_breaks) = skip_whitespace(text, after_destination);
+    if line_breaks > 1 {
+        return None;
+    }
+    let (title, after_title) = if title_start > after_destination || destination.is_empty() {
+        match parse_link_title(text, title_start) {
+            Some(result) => result,
+            None => ("".to_string(), title_start),
+        }
     } else {
-        length = start_emph.length;
-        start_pos = start_emph.pos;
-        end_pos = end_emph.pos;
+        ("".to_string(), title_start)
+    };
+
+    let (end, line_breaks) = skip_whitespace(text, after_title);
+    if line_breaks > 1 || !text.slice_from(end).starts_with(")") {
+        return None;
     }
-    return (start_pos, end_pos, length);
+    return Some((destination, title, end + 1));
 }
 
 
-pub fn parse_inline(s: String) -> InlineText {
-    //return parse_emphasis_and_strong(s.as_slice());
-    return parse_autolinks_code_and_html(s.as_slice());
-    //return vec![Inline::Text(s)];
+/// parses a link reference definition at the start of `text`, which may span multiple lines
+///
+/// Returns the label as written, the destination, the title and the number of bytes the
+/// definition takes up, which always ends with a complete line.
+pub fn parse_link_reference_definition(text: &str) -> Option<(String, String, String, uint)> {
+    let (label_start, _) = skip_whitespace(text, 0);
+    let (label, after_label) = match parse_link_label(text, label_start) {
+        Some((label, after_label)) => (label, after_label),
+        None => return None,
+    };
+    if label.trim().is_empty() || !text.slice_from(after_label).starts_with(":") {
+        return None;
+    }
+
+    let (destination_start, line_breaks) = skip_whitespace(text, after_label + 1);
+    if line_breaks > 1 {
+        return None;
+    }
+    let (destination, after_destination) = match parse_link_destination(text, destination_start) {
+        Some(result) => result,
+        None => return None,
+    };
+
+    // the title has to be separated from the destination by whitespace
+    let (title_start, line_breaks) = skip_whitespace(text, after_destination);
+    if line_breaks <= 1 && title_start > after_destination {
+        if let Some((title, after_title)) = parse_link_title(text, title_start) {
+            if let Some(end) = end_of_line(text, after_title) {
+                return Some((label.to_string(), destination, title, end));
+            }
+        }
+    }
+
+    // no valid title, then the destination has to end the line
+    return match end_of_line(text, after_destination) {
+        Some(end) => Some((label.to_string(), destination, "".to_string(), end)),
+        None => None,
+    }
+}
+
+
+/// a line of inline content whose parsing is deferred until all link reference definitions are
+/// known
+pub fn unparsed(s: String, span: Span) -> Spanned<Inline> {
+    return Spanned { node: Inline::Text(s), span: span };
+}
+
+
+/// the text content of inline elements without any markup, e.g. for the alt attribute of images
+/// or the ids of headers
+pub fn plain_text(text: &InlineText) -> String {
+    let mut result = String::new();
+    for inline in text.iter() {
+        push_plain_text(&mut result, &inline.node);
+    }
+    return result;
+}
+
+fn push_plain_text(out: &mut String, inline: &Inline) {
+    match *inline {
+        Inline::URIAutolink(ref s) | Inline::EmailAutolink(ref s) | Inline::HTMLTag(ref s) |
+                Inline::CodeSpan(ref s) | Inline::Text(ref s) => {
+            out.push_str(s.as_slice());
+        },
+        Inline::Link(ref content, _, _) | Inline::Image(ref content, _, _) |
+                Inline::Emph(ref content) | Inline::Strong(ref content) |
+                Inline::Strikethrough(ref content) => {
+            for inline in content.iter() {
+                push_plain_text(out, &inline.node);
+            }
+        },
+        Inline::FootnoteReference(_, number) => {
+            out.push_str(number.to_string().as_slice());
+        },
+        Inline::HardBreak | Inline::SoftBreak => {
+            out.push(' ');
+        },
+    }
+}
+
+
+/// parses the inline content of a block from its unparsed lines, and records in `usage` which
+/// link reference definitions it uses
+pub fn parse_inline(unparsed_lines: &[Spanned<Inline>], refs: &LinkReferenceMap, usage: &mut ReferenceUsage,
+                    footnotes: &HashSet<String>, source_map: &SourceMap,
+                    options: &ParseOptions) -> InlineText {
+    let mut joined = String::new();
+    let mut lines: Vec<Line> = Vec::new();
+    for line in unparsed_lines.iter() {
+        if let Inline::Text(ref text) = line.node {
+            if !lines.is_empty() {
+                joined.push('\n');
+            }
+            lines.push(Line { text_pos: joined.len(), length: text.len(), end: line.span.end });
+            joined.push_str(text.as_slice());
+        }
+    }
+
+    // leading and trailing whitespace doesn't belong to the content
+    let text = joined.as_slice().trim_left();
+    let base = joined.len() - text.len();
+    return InlineParser::new(text.trim_right(), base, lines.as_slice(), refs, usage, footnotes, source_map,
+                             options).parse();
 }
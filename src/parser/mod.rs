@@ -1,9 +1,17 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::default::Default;
 use std::iter::repeat;
+use std::mem;
 use regex::Regex;
 
-mod inline;
+use self::preprocess::SourceMap;
+use toc;
+
+pub mod inline;
+pub mod events;
+pub mod front_matter;
+mod entities;
 mod preprocess;
 
 
@@ -19,27 +27,205 @@ static RX_LIST_ITEM:Regex = regex!(r"^( {0,3}([-*+]|\d+[.)]) )( *)(.*)$");
 static RX_CODE_FENCE_START: Regex = regex!(r"^( {0,3})(`{3,}|~{3,}) *([^`]*?) *$");
 static RX_CODE_FENCE_END: Regex = regex!(r"^ {0,3}(`{3,}|~{3,}) *$");
 static RX_LINK_REFERENCE_DEFINITION_START: Regex = regex!(r"^ {0,3}\[((?:[^\]]|\\\])+)\]:(.*)$");
-static RX_HTML_BLOCK: Regex = regex!(r"^ {0,3}<(?:/?(?i:article|header|aside|hgroup|blockquote|hr|iframe|body|li|map|button|object|canvas|ol|caption|output|col|p|colgroup|pre|dd|progress|div|section|dl|table|td|dt|tbody|embed|textarea|fieldset|tfoot|figcaption|th|figure|thead|footer|tr|form|ul|h1|h2|h3|h4|h5|h6|video|script|style)|!|\?)");
+static RX_HEADER_ID: Regex = regex!(r"^(.*?) *\{#([A-Za-z][A-Za-z0-9_:.-]*)\}$");
+static RX_FOOTNOTE_DEFINITION: Regex = regex!(r"^ {0,3}\[\^([^\]\s]+)\]: ?(.*)$");
+static RX_TASK_LIST_MARKER: Regex = regex!(r"^\[([ xX])\](?: +|$)");
+static RX_TABLE_DELIMITER_ROW: Regex = regex!(r"^ {0,3}\|? *:?-+:? *(?:\| *:?-+:? *)*\|? *$");
+// start and end conditions of the seven kinds of HTML blocks
+static RX_HTML_BLOCK_START_1: Regex = regex!(r"^ {0,3}<(?i:script|pre|style|textarea)(?:\s|>|$)");
+static RX_HTML_BLOCK_END_1: Regex = regex!(r"(?i:</(?:script|pre|style|textarea)>)");
+static RX_HTML_BLOCK_START_2: Regex = regex!(r"^ {0,3}<!--");
+static RX_HTML_BLOCK_END_2: Regex = regex!(r"-->");
+static RX_HTML_BLOCK_START_3: Regex = regex!(r"^ {0,3}<\?");
+static RX_HTML_BLOCK_END_3: Regex = regex!(r"\?>");
+static RX_HTML_BLOCK_START_4: Regex = regex!(r"^ {0,3}<![A-Z]");
+static RX_HTML_BLOCK_END_4: Regex = regex!(r">");
+static RX_HTML_BLOCK_START_5: Regex = regex!(r"^ {0,3}<!\[CDATA\[");
+static RX_HTML_BLOCK_END_5: Regex = regex!(r"\]\]>");
+static RX_HTML_BLOCK_START_6: Regex = regex!(r"^ {0,3}</?(?i:address|article|aside|base|basefont|blockquote|body|caption|center|col|colgroup|dd|details|dialog|dir|div|dl|dt|fieldset|figcaption|figure|footer|form|frame|frameset|h1|h2|h3|h4|h5|h6|head|header|hr|html|iframe|legend|li|link|main|menu|menuitem|nav|noframes|ol|optgroup|option|p|param|section|source|summary|table|tbody|td|tfoot|th|thead|title|tr|track|ul)(?:\s|/?>|$)");
+static RX_HTML_BLOCK_START_7: Regex = regex!(r#"^ {0,3}(?:<[A-Za-z][A-Za-z0-9-]*(?:\s+[a-zA-Z_:][a-zA-Z0-9_.:-]*(?:\s*=\s*(?:[^"'=<>`\x00-\x20]+|'[^']*'|"[^"]*"))?)*\s*/?>|</[A-Za-z][A-Za-z0-9-]*\s*>)\s*$"#);
+
+/// options for parsing
+///
+/// The default options parse strict CommonMark.
+#[derive(Show,Clone)]
+pub struct ParseOptions {
+    /// the distance of tab stops, where 0 counts as 1. CommonMark uses 4. Code blocks keep the
+    /// tabs of the source after their indentation
+    pub tab_width: uint,
+    /// replace straight quotes by curly quotes, "--" and "---" by en and em dashes and "..." by
+    /// an ellipsis
+    pub smart_punctuation: bool,
+    /// GitHub Flavored Markdown pipe tables
+    pub tables: bool,
+    /// GitHub Flavored Markdown strikethrough with "~~"
+    pub strikethrough: bool,
+    /// GitHub Flavored Markdown task list items starting with "[ ]" or "[x]"
+    pub tasklists: bool,
+    /// GitHub Flavored Markdown autolinks of URLs, `www.` links and email addresses without
+    /// angle brackets
+    pub autolinks: bool,
+    /// footnote references like "[^1]" and footnote definitions like "[^1]: text"
+    pub footnotes: bool,
+    /// YAML front matter between "---" lines or TOML front matter between "+++" lines at the
+    /// start of the document, which isn't parsed as Markdown
+    pub front_matter: bool,
+    /// give every header an id, either from a "{#id}" at the end of its text or GitHub style
+    /// from its text. Duplicate ids get a number appended
+    pub header_ids: bool,
+}
 
-#[derive(Show)]
-enum Block<'r> {
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            tab_width: 4,
+            smart_punctuation: false,
+            tables: false,
+            strikethrough: false,
+            tasklists: false,
+            autolinks: false,
+            footnotes: false,
+            front_matter: false,
+            header_ids: false,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// the default options with all GitHub Flavored Markdown extensions enabled
+    pub fn gfm() -> ParseOptions {
+        ParseOptions {
+            tables: true,
+            strikethrough: true,
+            tasklists: true,
+            autolinks: true,
+            footnotes: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// a position in the Markdown source
+#[derive(Show,Clone,Copy,PartialEq)]
+pub struct Position {
+    /// the line, starting at 1
+    pub line: uint,
+    /// the column in characters of the line, starting at 1. A tab counts as one character
+    pub column: uint,
+    /// the offset in bytes from the start of the source
+    pub offset: uint,
+}
+
+/// the part of the Markdown source an element was parsed from
+#[derive(Show,Clone,Copy,PartialEq)]
+pub struct Span {
+    pub start: Position,
+    /// the position right after the element
+    pub end: Position,
+}
+
+/// an element together with its span in the Markdown source
+#[derive(Show,Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+/// a parsed Markdown document
+#[derive(Show,Clone)]
+pub struct Document {
+    /// the top level blocks of the document
+    pub blocks: Vec<Spanned<Block>>,
+    /// the referenced footnotes, in the order of their first reference
+    pub footnotes: Vec<Footnote>,
+    /// the footnotes that aren't referenced, in the order of the source. They aren't rendered
+    /// as HTML
+    pub unreferenced_footnotes: Vec<Footnote>,
+    /// the front matter at the start of the document, if the option is enabled
+    pub front_matter: Option<front_matter::FrontMatter>,
+    /// the link reference definitions, in the order of the source. Of several definitions with
+    /// the same label, only the first is used and kept
+    pub link_references: Vec<LinkReference>,
+    /// the labels of full and collapsed reference links like `[text][label]` whose label isn't
+    /// defined, so that they are left as text
+    pub undefined_references: Vec<Spanned<String>>,
+}
+
+/// a link reference definition like `[label]: /url "title"`
+#[derive(Show,Clone)]
+pub struct LinkReference {
+    /// the label as written in the definition
+    pub label: String,
+    pub destination: String,
+    pub title: String,
+    pub span: Span,
+    /// whether a link or image refers to the definition by its label
+    pub used: bool,
+}
+
+/// a footnote definition
+#[derive(Show,Clone)]
+pub struct Footnote {
+    /// the label as written in the definition, without "^"
+    pub label: String,
+    /// the number of the footnote, counting from 1 in the order of the first references. The
+    /// unreferenced footnotes have none and keep 0
+    pub number: uint,
+    /// the blocks the footnote consists of
+    pub blocks: Vec<Spanned<Block>>,
+    /// the span from the label to the end of the definition
+    pub span: Span,
+}
+
+/// a block level element
+#[derive(Show,Clone)]
+pub enum Block {
+    /// a horizontal rule
     Rule,
-    Header(uint, inline::InlineText),
+    /// an ATX or setext header with its level (1 to 6), text and id, which is only given with
+    /// the header ids option
+    Header(uint, inline::InlineText, Option<String>),
     Paragraph(inline::InlineText),
+    /// an indented or fenced code block with the info string of the opening fence, which is `None`
+    /// for indented code, and the code
     Code(Option<inline::InlineText>, String),
-    BlockQuote(Box<Vec<Block<'r>>>),
-    HTMLBlock(inline::InlineText),
-    List(bool, Vec<ListItem<'r>>),
+    BlockQuote(Box<Vec<Spanned<Block>>>),
+    /// raw HTML, which is passed through unchanged
+    HTMLBlock(String),
+    /// a list, which is tight if none of its items are separated by blank lines
+    List(bool, Vec<ListItem>),
+    /// a table with the alignments of its columns, the cells of its header row and its body rows
+    Table(Vec<Alignment>, Vec<inline::InlineText>, Vec<Vec<inline::InlineText>>),
 }
 
-#[derive(Show)]
-struct ListItem<'r> {
-    blocks: Box<Vec<Block<'r>>>,
-    listtype: ListType,
+/// the alignment of a table column, given by the colons in the delimiter row
+#[derive(Show,Clone,Copy,PartialEq)]
+pub enum Alignment {
+    None,
+    Left,
+    Center,
+    Right,
+}
+
+/// an item of a `Block::List`
+#[derive(Show,Clone)]
+pub struct ListItem {
+    /// the blocks the list item consists of
+    pub blocks: Box<Vec<Spanned<Block>>>,
+    /// the kind of list marker of the item
+    pub listtype: ListType,
+    /// whether the item of a task list is checked, or `None` if it isn't a task list item
+    pub checked: Option<bool>,
+    /// the span from the list marker to the end of the item
+    pub span: Span,
 }
 
+/// the list marker of a list item
+///
+/// Two list types are equal if they use the same marker character, regardless of the start
+/// number. Those are the list items that belong to the same list.
 #[derive(Show,Clone)]
-enum ListType {
+pub enum ListType {
     Ordered(uint, char),    // start number and '.' or ')'
     Unordered(char)         // '-' or '+' or '*'
 }
@@ -75,13 +261,27 @@ struct ParseState<'r> {
     pos: uint,
     in_paragraph: bool,
     container_stack: Vec<ContainerType>,
-    //link_reference_defs: Vec<HashMap<String, (String,String)>>,
+    link_reference_defs: inline::LinkReferenceMap,
+    link_references: Vec<LinkReference>,
+    footnote_defs: Vec<Footnote>,
+    source_map: &'r SourceMap,
+    options: &'r ParseOptions,
 }
 
 
 impl<'r> ParseState<'r> {
-    fn new(s: &mut[String]) -> ParseState {
-        ParseState { s: s, pos: 0, in_paragraph: false, container_stack: Vec::new() }
+    fn new(s: &'r mut[String], source_map: &'r SourceMap, options: &'r ParseOptions) -> ParseState<'r> {
+        ParseState {
+            s: s,
+            pos: 0,
+            in_paragraph: false,
+            container_stack: Vec::new(),
+            link_reference_defs: HashMap::new(),
+            link_references: Vec::new(),
+            footnote_defs: Vec::new(),
+            source_map: source_map,
+            options: options
+        }
     }
 
     fn strip_container_prefixes<'p>(&'p self, line: &'p str) -> Option<&str> {
@@ -147,7 +347,48 @@ impl<'r> ParseState<'r> {
         self.pos += 1;
     }
 
-    fn parse_blocks(&mut self) -> (Vec<Block<'r>>, bool, bool) {
+    /// the position of the first non-space character of the current line
+    fn line_start_position(&self) -> Position {
+        let from_end = match self.current_line() {
+            None => 0,
+            Some(line) => line.as_slice().trim_left().len()
+        };
+        return self.source_map.position_from_end(self.pos, from_end);
+    }
+
+    /// the end of the last non-blank line before the current line, but not before the line
+    /// of `start`
+    fn block_end_position(&self, start: Position) -> Position {
+        let mut line = if self.pos > 0 { self.pos - 1 } else { 0 };
+        while line >= start.line && self.is_blank_line(line) {
+            line -= 1;
+        }
+        return self.source_map.position_from_end(line, 0);
+    }
+
+    /// whether a line is blank without the prefixes of the containers, so a line of only ">"
+    /// is blank inside of a block quote
+    fn is_blank_line(&self, line: uint) -> bool {
+        return match self.strip_container_prefixes(self.s[line].as_slice()) {
+            Some(stripped_line) => stripped_line.trim().is_empty(),
+            None => self.source_map.is_blank(line),
+        }
+    }
+
+    /// the span of `length` bytes of the current line that end `from_end` bytes before its end
+    fn line_span(&self, length: uint, from_end: uint) -> Span {
+        return Span {
+            start: self.source_map.position_from_end(self.pos, from_end),
+            end: self.source_map.position_from_end(self.pos, from_end - length),
+        };
+    }
+
+    /// a block that started at `start` and ends before the current line
+    fn spanned<T>(&self, node: T, start: Position) -> Spanned<T> {
+        return Spanned { node: node, span: Span { start: start, end: self.block_end_position(start) } };
+    }
+
+    fn parse_blocks(&mut self) -> (Vec<Spanned<Block>>, bool, bool) {
         let mut blocks = Vec::new();
         let mut no_blank_line = true;
         let mut more_than_2_empty_lines = false;
@@ -164,41 +405,14 @@ impl<'r> ParseState<'r> {
                 no_blank_line = false;
             }
 
-            self.parse_link_reference_definition();
-
-            if let Some(block) = self.parse_horizontal_rule() {
-                blocks.push(block);
+            if self.parse_footnote_definition() || self.parse_link_reference_definition() {
                 continue;
             }
 
-            
-            if let Some(block) = self.parse_atx_header() {
-                blocks.push(block);
-                continue;
-            }
-            
-            if let Some(block) = self.parse_indented_code_block() {
-                blocks.push(block);
-                continue;
-            }
+            let start = self.line_start_position();
 
-            if let Some(block) = self.parse_html_block() {
-                blocks.push(block);
-                continue;
-            }
-
-            if let Some(block) = self.parse_fenced_code_block() {
-                blocks.push(block);
-                continue;
-            }
-
-            if let Some(block) = self.parse_blockquote() {
-                blocks.push(block);
-                continue;
-            }
-
-            if let Some((block, end_all_lists)) = self.parse_list() {
-                blocks.push(block);
+            if let Some((block, end_all_lists)) = self.parse_block() {
+                blocks.push(self.spanned(block, start));
                 if end_all_lists {
                     more_than_2_empty_lines = true;
                     if !self.container_stack.is_empty() {
@@ -206,22 +420,9 @@ impl<'r> ParseState<'r> {
                             break;
                         }
                     }
-                    continue;
-                } else {
-                    continue;
                 }
             }
 
-            if let Some(block) = self.parse_setext_header() {
-                blocks.push(block);
-                continue;
-            }
-
-            if let Some(block) = self.parse_paragraph() {
-                blocks.push(block);
-                continue;
-            }
-
         }
 
         if !self.container_stack.is_empty() {
@@ -231,6 +432,68 @@ impl<'r> ParseState<'r> {
         return (blocks, no_blank_line, more_than_2_empty_lines);
     }
 
+    /// parses the block at the current line, and also returns whether it is a list that ends
+    /// all enclosing lists because of two blank lines
+    fn parse_block(&mut self) -> Option<(Block, bool)> {
+        if let Some(block) = self.parse_horizontal_rule() {
+            return Some((block, false));
+        }
+
+        if let Some(block) = self.parse_atx_header() {
+            return Some((block, false));
+        }
+
+        if let Some(block) = self.parse_indented_code_block() {
+            return Some((block, false));
+        }
+
+        if let Some(block) = self.parse_html_block() {
+            return Some((block, false));
+        }
+
+        if let Some(block) = self.parse_fenced_code_block() {
+            return Some((block, false));
+        }
+
+        if let Some(block) = self.parse_blockquote() {
+            return Some((block, false));
+        }
+
+        if let Some(result) = self.parse_list() {
+            return Some(result);
+        }
+
+        if let Some(block) = self.parse_setext_header() {
+            return Some((block, false));
+        }
+
+        if let Some(block) = self.parse_table() {
+            return Some((block, false));
+        }
+
+        if let Some(block) = self.parse_paragraph() {
+            return Some((block, false));
+        }
+
+        return None;
+    }
+
+    /// parses the next top level block, skipping blank lines, footnote definitions and link
+    /// reference definitions
+    fn parse_top_level_block(&mut self) -> Option<Spanned<Block>> {
+        while self.current_line().is_some() {
+            self.skip_empty_lines();
+            if self.parse_footnote_definition() || self.parse_link_reference_definition() {
+                continue;
+            }
+            let start = self.line_start_position();
+            if let Some((block, _)) = self.parse_block() {
+                return Some(self.spanned(block, start));
+            }
+        }
+        return None;
+    }
+
 
     fn see_empty_line(&self) -> bool {
         return match self.current_line() {
@@ -266,7 +529,7 @@ impl<'r> ParseState<'r> {
     }
 
 
-    fn parse_horizontal_rule(&mut self) -> Option<Block<'r>> {
+    fn parse_horizontal_rule(&mut self) -> Option<Block> {
         let line = self.current_line();
         if line.is_some() && RX_HORIZONTAL_RULE.is_match(line.unwrap().as_slice()) {
             self.onwards();
@@ -284,32 +547,54 @@ impl<'r> ParseState<'r> {
         }
     }
 
-    fn parse_atx_header(&mut self) -> Option<Block<'r>> {
-        let line = self.current_line();
-        if line.is_none() {
-            return None;
-        }
-        match RX_HEADER_ATX.captures(line.unwrap().as_slice()) {
+    fn parse_atx_header(&mut self) -> Option<Block> {
+        let line = match self.current_line() {
+            None => return None,
+            Some(line) => line
+        };
+        match RX_HEADER_ATX.captures(line.as_slice()) {
             None => {
                 return None;
             },
             Some(cap) => {
                 let level = cap.at(1).unwrap().len();
-                let matched_str = cap.at(2).unwrap();
+                let (text, id) = match cap.pos(2) {
+                    None => (Vec::new(), None),
+                    Some((start, end)) => self.header_content(line.as_slice(), start, end)
+                };
                 self.onwards();
-                return Some(Block::Header(level, inline::parse_inline(matched_str.to_string())));
+                return Some(Block::Header(level, text, id));
             }
         }
     }
 
 
-    fn parse_setext_header(&mut self) -> Option<Block<'r>> {
+    /// the unparsed text of a header from `start` to `end` of the current line, and with the
+    /// header ids option the id of a "{#id}" at its end
+    fn header_content(&self, line: &str, start: uint, end: uint) -> (inline::InlineText, Option<String>) {
+        let mut end = end;
+        let mut id = None;
+        if self.options.header_ids {
+            if let Some(cap) = RX_HEADER_ID.captures(line.slice(start, end)) {
+                id = Some(cap.at(2).unwrap().to_string());
+                end = start + cap.at(1).unwrap().len();
+            }
+        }
+        if start == end {
+            return (Vec::new(), id);
+        }
+        let text = inline::unparsed(line.slice(start, end).to_string(), self.line_span(end - start, line.len() - start));
+        return (vec![text], id);
+    }
+
+    fn parse_setext_header(&mut self) -> Option<Block> {
         let current_line = self.current_line();
         let next_line = self.next_line();
         if current_line.is_none() || next_line.is_none() {
             return None;
         }
-        match RX_HEADER_SETEXT_TEXT.captures(current_line.unwrap().as_slice()) {
+        let current_line = current_line.unwrap();
+        match RX_HEADER_SETEXT_TEXT.captures(current_line.as_slice()) {
             None => {
                 return None;
             },
@@ -320,10 +605,11 @@ impl<'r> ParseState<'r> {
                     },
                     Some(cap_line) => {
                         let level = if cap_line.at(1).unwrap().char_at(0) == '=' { 1 } else { 2 };
-                        let matched_str = cap_text.at(1).unwrap();
+                        let (start, end) = cap_text.pos(1).unwrap();
+                        let (text, id) = self.header_content(current_line.as_slice(), start, end);
                         self.onwards();
                         self.onwards();
-                        return Some(Block::Header(level, inline::parse_inline(matched_str.to_string())));
+                        return Some(Block::Header(level, text, id));
                     }
                 }
             }
@@ -331,7 +617,7 @@ impl<'r> ParseState<'r> {
     }
 
 
-    fn parse_indented_code_block(&mut self) -> Option<Block<'r>> {
+    fn parse_indented_code_block(&mut self) -> Option<Block> {
         let mut is_indented_code_block = false;
         let mut code_string: Vec<String> = Vec::new(); //XXX wenn ich hier &str nehme, passieren komische Sachen
         loop {
@@ -341,14 +627,14 @@ impl<'r> ParseState<'r> {
                     match RX_INDENTED_CODE_LINE_NO_BLANK_LINE.captures(line.as_slice()) {
                         Some(cap) => {
                             is_indented_code_block = true;
-                            code_string.push(cap.at(1).unwrap().to_string());
+                            code_string.push(self.source_map.original_text_from_end(self.pos, cap.at(1).unwrap().len()));
                             self.onwards();
                         },
                         None => {
                             match RX_INDENTED_CODE_LINE_BLANK_LINE.captures(line.as_slice()) {
                                 Some(cap) => {
                                     is_indented_code_block = true;
-                                    code_string.push(cap.at(1).unwrap().to_string());
+                                    code_string.push(self.source_map.original_text_from_end(self.pos, cap.at(1).unwrap().len()));
                                     self.onwards();
                                 },
                                 None => { break; }
@@ -359,9 +645,9 @@ impl<'r> ParseState<'r> {
             }
         }
 
-        // delete trailing blank lines
+        // delete trailing blank lines, which keep the tabs of the source like all code lines
         while !code_string.is_empty() &&
-                    RX_BLANK_LINE.is_match(code_string[code_string.len()-1].as_slice()) {
+                    code_string[code_string.len()-1].as_slice().chars().all(|c| c == ' ' || c == '\t') {
             code_string.pop();
         }
 
@@ -385,13 +671,13 @@ impl<'r> ParseState<'r> {
         }
     }
 
-    fn parse_fenced_code_block(&mut self) -> Option<Block<'r>> {
+    fn parse_fenced_code_block(&mut self) -> Option<Block> {
         let mut code_string: Vec<String> = Vec::new();
-        let line = self.current_line();
-        if line.is_none() {
-            return None;
-        }
-        match RX_CODE_FENCE_START.captures(line.unwrap().as_slice()) {
+        let line = match self.current_line() {
+            None => return None,
+            Some(line) => line
+        };
+        match RX_CODE_FENCE_START.captures(line.as_slice()) {
             None => {
                 return None;
             },
@@ -399,10 +685,13 @@ impl<'r> ParseState<'r> {
                 let starting_fence_indent = cap.at(1).unwrap().len();
                 let starting_fence_char = cap.at(2).unwrap().char_at(0);
                 let starting_fence_len = cap.at(2).unwrap().len();
-                let info_string = if cap.at(3).unwrap().len() > 0 {
-                    Some(inline::parse_inline(cap.at(3).unwrap().to_string()))
-                } else {
-                    None
+                let info_string = match cap.pos(3) {
+                    Some((start, end)) if end > start => {
+                        let span = self.line_span(end - start, line.len() - start);
+                        let info = inline::unescape(cap.at(3).unwrap());
+                        Some(vec![Spanned { node: inline::Inline::Text(info), span: span }])
+                    },
+                    _ => Some(Vec::new())
                 };
 
                 loop {
@@ -429,7 +718,7 @@ impl<'r> ParseState<'r> {
                         }
                     }
 
-                    code_string.push(line_slice.to_string());
+                    code_string.push(self.source_map.original_text_from_end(self.pos, line_slice.len()));
                 }
 
                 self.onwards();
@@ -452,7 +741,7 @@ impl<'r> ParseState<'r> {
         }
     }
 
-    fn parse_blockquote(&mut self) -> Option<Block<'r>> {
+    fn parse_blockquote(&mut self) -> Option<Block> {
         if self.see_blockquote() {
             self.container_stack.push(ContainerType::BQ);
             let blocks = box self.parse_document();
@@ -474,25 +763,35 @@ impl<'r> ParseState<'r> {
         }
     }
 
-    fn parse_list_item(&mut self) -> Option<(ListItem<'r>, bool, bool)> {
-        let line = self.current_line();
-        if line.is_none() {
-            return None;
-        }
-        match RX_LIST_ITEM.captures(line.unwrap().as_slice()) {
+    fn parse_list_item(&mut self) -> Option<(ListItem, bool, bool)> {
+        let line = match self.current_line() {
+            None => return None,
+            Some(line) => line
+        };
+        match RX_LIST_ITEM.captures(line.as_slice()) {
             None => {
                 return None;
             },
             Some(cap) => {
                 let marker = cap.at(2).unwrap();
                 let list_type = get_list_type(marker);
+                let start = self.source_map.position_from_end(self.pos, line.len() - cap.pos(2).unwrap().0);
                 let number_of_additional_spaces = cap.at(3).unwrap().len();
                 let marker_pos = self.s[self.pos].find_str(marker).unwrap();
                 let mut line_rest: String;
                 let mut width;
+                let mut checked = None;
                 if number_of_additional_spaces <= 3 {
                     width = cap.at(1).unwrap().len() + number_of_additional_spaces;
-                    line_rest = repeat(' ').take(marker_pos + width).collect::<String>() + cap.at(4).unwrap();
+                    let mut content = cap.at(4).unwrap();
+                    // the task list marker isn't part of the content
+                    if self.options.tasklists {
+                        if let Some(task_cap) = RX_TASK_LIST_MARKER.captures(content) {
+                            checked = Some(task_cap.at(1).unwrap() != " ");
+                            content = content.slice_from(task_cap.at(0).unwrap().len());
+                        }
+                    }
+                    line_rest = repeat(' ').take(marker_pos + width).collect::<String>() + content;
                 } else { // list item starting with indented code
                     width = cap.at(1).unwrap().len();
                     line_rest = repeat(' ').take(marker_pos + width + number_of_additional_spaces).collect::<String>() + cap.at(4).unwrap();
@@ -500,12 +799,14 @@ impl<'r> ParseState<'r> {
                 self.s[self.pos] = line_rest;
                 self.container_stack.push(ContainerType::LI(width));
                 let (blocks, is_tight, end_list) = self.parse_blocks();
-                return Some((ListItem { blocks: box blocks, listtype: list_type }, is_tight, end_list));
+                let span = Span { start: start, end: self.block_end_position(start) };
+                let item = ListItem { blocks: box blocks, listtype: list_type, checked: checked, span: span };
+                return Some((item, is_tight, end_list));
             }
         }
     }
 
-    fn parse_list(&mut self) -> Option<(Block<'r>, bool)> {
+    fn parse_list(&mut self) -> Option<(Block, bool)> {
         let first_list_item = self.parse_list_item();
         if first_list_item.is_none() {
             return None;
@@ -557,80 +858,135 @@ impl<'r> ParseState<'r> {
         return Some((Block::List(tight, list_items), end_list));
     }
 
-    fn see_html_block(&self) -> bool {
+    /// the kind of the HTML block that starts at the current line, from 1 to 7
+    fn see_html_block(&self) -> Option<uint> {
         return match self.current_line() {
-            None => false,
-            Some(line) => RX_HTML_BLOCK.is_match(line.as_slice())
-        }
-    }
-
-    fn parse_html_block(&mut self) -> Option<Block<'r>> {
-        if self.see_html_block() {
-            let mut block_string = self.current_line().unwrap();
-            block_string.push('\n');
-            loop {
-                self.onwards();
-
-                if self.see_empty_line() {
-                    break;
-                }
-
-                match self.current_line() {
-                    None => break,
-                    Some(line) => {
-                        block_string.push_str(line.as_slice());
-                        block_string.push('\n');
-                    }
-                }
-            }
-            return Some(Block::HTMLBlock(inline::parse_inline(block_string)));
-        } else {
-            return None;
+            None => None,
+            Some(line) => html_block_start(line.as_slice())
         }
     }
 
-    fn parse_paragraph(&mut self) -> Option<Block<'r>> {
-        let mut is_paragraph = false;
-        let mut paragraph_string: String = "".to_string();
-        //XXX die erste Zeile ist immer ne Paragrafenzeile, da muss man nix prüfen
+    fn parse_html_block(&mut self) -> Option<Block> {
+        let kind = match self.see_html_block() {
+            None => return None,
+            Some(kind) => kind
+        };
+        let mut block_string: String = "".to_string();
         loop {
-            let current_line = self.current_line();
+            let line = match self.current_line() {
+                None => break,
+                Some(line) => line
+            };
 
-            if current_line.is_none() {
+            // kinds 6 and 7 end before a blank line, the others with the line of their end condition
+            if kind >= 6 && RX_BLANK_LINE.is_match(line.as_slice()) {
                 break;
             }
-
-            if self.see_empty_line() {
+            block_string.push_str(line.as_slice());
+            block_string.push('\n');
+            self.onwards();
+            if html_block_ends(kind, line.as_slice()) {
                 break;
             }
+        }
+        return Some(Block::HTMLBlock(block_string));
+    }
 
-            if self.see_atx_header() {
-                break;
-            }
+    /// whether the current line is a blank line or starts a block that interrupts a paragraph,
+    /// other than a table
+    fn see_block_interruption(&self) -> bool {
+        return self.see_empty_line()
+            || self.see_atx_header()
+            || self.see_horizontal_rule()
+            || self.see_html_block().map_or(false, |kind| kind != 7)
+            || self.see_blockquote()
+            || self.see_list_item().is_some()
+            || self.see_fenced_code_block();
+    }
 
-            if self.see_horizontal_rule() {
-                break;
-            }
+    /// whether the current line ends a paragraph
+    fn see_paragraph_interruption(&self) -> bool {
+        return self.see_block_interruption() || self.see_table() || self.see_footnote_definition();
+    }
 
-            if self.see_html_block() {
-                break;
-            }
+    fn see_footnote_definition(&self) -> bool {
+        if !self.options.footnotes {
+            return false;
+        }
+        return match self.current_line() {
+            None => false,
+            Some(line) => RX_FOOTNOTE_DEFINITION.is_match(line.as_slice())
+        }
+    }
 
-            if self.see_blockquote() {
-                break;
-            }
+    /// whether the current line is the header row of a table, followed by a delimiter row with
+    /// the same number of cells. One of them must contain a pipe, so a "---" after a line isn't
+    /// a table
+    fn see_table(&self) -> bool {
+        if !self.options.tables {
+            return false;
+        }
+        return match (self.current_line(), self.next_line()) {
+            (Some(line), Some(next_line)) => {
+                RX_TABLE_DELIMITER_ROW.is_match(next_line.as_slice()) &&
+                    (has_unescaped_pipe(line.as_slice()) || has_unescaped_pipe(next_line.as_slice())) &&
+                    table_row_cells(line.as_slice()).len() == table_row_cells(next_line.as_slice()).len()
+            },
+            _ => false
+        }
+    }
 
-            if self.see_list_item().is_some() {
-                break;
-            }
+    /// parses a table, whose body rows end before a blank line or another block
+    fn parse_table(&mut self) -> Option<Block> {
+        if !self.see_table() {
+            return None;
+        }
+        let delimiter_row = self.next_line().unwrap();
+        let alignments: Vec<Alignment> = table_row_cells(delimiter_row.as_slice()).iter()
+            .map(|&(start, end)| table_column_alignment(delimiter_row.as_slice().slice(start, end)))
+            .collect();
+        let header = self.parse_table_row(alignments.len());
+        self.onwards();
+        self.onwards();
+
+        let mut rows = Vec::new();
+        while self.current_line().is_some() && !self.see_block_interruption() {
+            rows.push(self.parse_table_row(alignments.len()));
+            self.onwards();
+        }
+        return Some(Block::Table(alignments, header, rows));
+    }
+
+    /// the cells of the table row in the current line. Missing cells are empty, and excess cells
+    /// are left out
+    fn parse_table_row(&self, columns: uint) -> Vec<inline::InlineText> {
+        let line = self.current_line().unwrap();
+        let mut cells: Vec<inline::InlineText> = Vec::new();
+        for &(start, end) in table_row_cells(line.as_slice()).iter().take(columns) {
+            // escaped pipes are part of the cell, even in code spans
+            let cell = line.as_slice().slice(start, end).replace("\\|", "|");
+            cells.push(vec![inline::unparsed(cell, self.line_span(end - start, line.len() - start))]);
+        }
+        while cells.len() < columns {
+            cells.push(Vec::new());
+        }
+        return cells;
+    }
+
+    fn parse_paragraph(&mut self) -> Option<Block> {
+        let mut is_paragraph = false;
+        let mut paragraph_lines: inline::InlineText = Vec::new();
+        //XXX die erste Zeile ist immer ne Paragrafenzeile, da muss man nix prüfen
+        loop {
+            let current_line = self.current_line();
 
-            if self.see_fenced_code_block() {
+            if current_line.is_none() || self.see_paragraph_interruption() {
                 break;
             }
 
             is_paragraph = true;
-            paragraph_string.push_str(current_line.unwrap().as_slice());
-            paragraph_string.push('\n');
+            let line = current_line.unwrap();
+            paragraph_lines.push(inline::unparsed(line.clone(), self.line_span(line.len(), line.len())));
             self.in_paragraph = true;
             self.onwards();
         }
@@ -638,21 +994,101 @@ impl<'r> ParseState<'r> {
         self.in_paragraph = false;
 
         return if is_paragraph {
-            Some(Block::Paragraph(inline::parse_inline(paragraph_string)))
+            Some(Block::Paragraph(paragraph_lines))
         } else {
             None
         }
     }
 
-    fn parse_link_reference_definition(&mut self) {
-        if let Some(line) = self.current_line() {
-            if let Some(cap) = RX_LINK_REFERENCE_DEFINITION_START.captures(line.as_slice()) {
+    /// parses a link reference definition and remembers it, unless the label is already defined
+    ///
+    /// A definition can span several lines, which are collected like the lines of a paragraph.
+    fn parse_link_reference_definition(&mut self) -> bool {
+        match self.current_line() {
+            None => return false,
+            Some(line) => {
+                if !RX_LINK_REFERENCE_DEFINITION_START.is_match(line.as_slice()) {
+                    return false;
+                }
+            }
+        }
 
+        let start_pos = self.pos;
+        let start = self.line_start_position();
+        let mut definition_string: String = "".to_string();
+        loop {
+            let current_line = self.current_line();
+            if current_line.is_none() || (self.pos > start_pos && self.see_paragraph_interruption()) {
+                break;
             }
+            definition_string.push_str(current_line.unwrap().as_slice());
+            definition_string.push('\n');
+            self.in_paragraph = true;
+            self.onwards();
         }
+        self.in_paragraph = false;
+        self.pos = start_pos;
+
+        match inline::parse_link_reference_definition(definition_string.as_slice()) {
+            None => {
+                return false;
+            },
+            Some((label, destination, title, length)) => {
+                let line_count = definition_string.slice_to(length).chars().filter(|&c| c == '\n').count();
+                self.pos += line_count;
+                let normalized_label = inline::normalize_label(label.as_slice());
+                if !self.link_reference_defs.contains_key(&normalized_label) {
+                    self.link_reference_defs.insert(normalized_label, (destination.clone(), title.clone()));
+                    let span = Span { start: start, end: self.block_end_position(start) };
+                    self.link_references.push(LinkReference {
+                        label: label, destination: destination, title: title, span: span, used: false
+                    });
+                }
+                return true;
+            }
+        }
+    }
+
+    /// parses a footnote definition and remembers it, unless the label is already defined
+    ///
+    /// The content starts after the colon and continues with the lines indented by 4 spaces,
+    /// like the content of a list item.
+    fn parse_footnote_definition(&mut self) -> bool {
+        if !self.options.footnotes {
+            return false;
+        }
+        let line = match self.current_line() {
+            None => return false,
+            Some(line) => line
+        };
+        let cap = match RX_FOOTNOTE_DEFINITION.captures(line.as_slice()) {
+            None => return false,
+            Some(cap) => cap
+        };
+        let label = cap.at(1).unwrap().to_string();
+        let start = self.line_start_position();
+
+        // the definition is replaced by indentation, keeping the prefixes of the containers
+        let prefix_length = self.s[self.pos].len() - line.len();
+        let line_rest = self.s[self.pos].slice_to(prefix_length).to_string() + "    " + cap.at(2).unwrap();
+        self.s[self.pos] = line_rest;
+        self.container_stack.push(ContainerType::LI(4));
+        let (blocks, _, _) = self.parse_blocks();
+        let span = Span { start: start, end: self.block_end_position(start) };
+
+        let normalized_label = inline::normalize_label(label.as_slice());
+        if !self.footnote_defs.iter().any(|f| inline::normalize_label(f.label.as_slice()) == normalized_label) {
+            self.footnote_defs.push(Footnote { label: label, number: 0, blocks: blocks, span: span });
+        }
+        return true;
     }
 
-    fn parse_document(&mut self) -> Vec<Block<'r>> {
+    /// the normalized labels of the footnote definitions
+    fn footnote_labels(&self) -> HashSet<String> {
+        return self.footnote_defs.iter().map(|f| inline::normalize_label(f.label.as_slice())).collect();
+    }
+
+    fn parse_document(&mut self) -> Vec<Spanned<Block>> {
         return self.parse_blocks().0;
     }
 
@@ -672,13 +1108,287 @@ fn get_list_type(marker: &str) -> ListType {
     }
 }
 
-pub fn parse_markdown(md_string: String) -> String {
-    let mut md_lines: Vec<String> = preprocess::preprocess_text(md_string.as_slice());
+/// whether a line contains a pipe that isn't escaped by a backslash
+fn has_unescaped_pipe(line: &str) -> bool {
+    let bytes = line.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b'|' => return true,
+            _ => pos += 1,
+        }
+    }
+    return false;
+}
+
+/// the byte ranges of the cells of a table row, without the surrounding whitespace and pipes
+fn table_row_cells(line: &str) -> Vec<(uint, uint)> {
+    let bytes = line.as_bytes();
+    let mut start = 0;
+    while start < bytes.len() && bytes[start] == b' ' {
+        start += 1;
+    }
+    if start < bytes.len() && bytes[start] == b'|' {
+        start += 1;
+    }
+    let mut end = bytes.len();
+    while end > start && bytes[end - 1] == b' ' {
+        end -= 1;
+    }
+    if end > start && bytes[end - 1] == b'|' && bytes[end - 2] != b'\\' {
+        end -= 1;
+    }
+
+    let mut cells = Vec::new();
+    let mut cell_start = start;
+    let mut pos = start;
+    while pos < end {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b'|' => {
+                cells.push(trim_cell(bytes, cell_start, pos));
+                pos += 1;
+                cell_start = pos;
+            },
+            _ => pos += 1,
+        }
+    }
+    cells.push(trim_cell(bytes, cell_start, end));
+    return cells;
+}
+
+fn trim_cell(bytes: &[u8], start: uint, end: uint) -> (uint, uint) {
+    let mut start = start;
+    let mut end = end;
+    while start < end && bytes[start] == b' ' {
+        start += 1;
+    }
+    while end > start && bytes[end - 1] == b' ' {
+        end -= 1;
+    }
+    return (start, end);
+}
+
+fn table_column_alignment(delimiter: &str) -> Alignment {
+    return match (delimiter.starts_with(":"), delimiter.ends_with(":")) {
+        (true, true) => Alignment::Center,
+        (true, false) => Alignment::Left,
+        (false, true) => Alignment::Right,
+        (false, false) => Alignment::None,
+    }
+}
+
+fn html_block_start(line: &str) -> Option<uint> {
+    let start_conditions = [
+        &RX_HTML_BLOCK_START_1, &RX_HTML_BLOCK_START_2, &RX_HTML_BLOCK_START_3, &RX_HTML_BLOCK_START_4,
+        &RX_HTML_BLOCK_START_5, &RX_HTML_BLOCK_START_6, &RX_HTML_BLOCK_START_7
+    ];
+    return start_conditions.iter().position(|rx| rx.is_match(line)).map(|i| i + 1);
+}
+
+fn html_block_ends(kind: uint, line: &str) -> bool {
+    return match kind {
+        1 => RX_HTML_BLOCK_END_1.is_match(line),
+        2 => RX_HTML_BLOCK_END_2.is_match(line),
+        3 => RX_HTML_BLOCK_END_3.is_match(line),
+        4 => RX_HTML_BLOCK_END_4.is_match(line),
+        5 => RX_HTML_BLOCK_END_5.is_match(line),
+        _ => false
+    }
+}
+
+pub fn parse_markdown(md_string: &str, options: &ParseOptions) -> Document {
+    let (mut md_lines, source_map) = preprocess::preprocess_text(md_string, options.tab_width);
+    let (front_matter, first_line) = find_front_matter(md_lines.as_slice(), &source_map, options);
+
+    let st = &mut ParseState::new(md_lines.as_mut_slice(), &source_map, options);
+    st.pos = first_line;
+
+    let mut parse_result = st.parse_document();
+    let footnote_labels = st.footnote_labels();
+    let mut usage: inline::ReferenceUsage = Default::default();
+    parse_inline_content(&mut parse_result, &st.link_reference_defs, &mut usage, &footnote_labels, &source_map,
+                         options);
+    let mut footnote_defs = mem::replace(&mut st.footnote_defs, Vec::new());
+    for footnote in footnote_defs.iter_mut() {
+        parse_inline_content(&mut footnote.blocks, &st.link_reference_defs, &mut usage, &footnote_labels,
+                             &source_map, options);
+    }
+    let (footnotes, unreferenced_footnotes) = number_footnotes(&mut parse_result, footnote_defs);
+    if options.header_ids {
+        assign_header_ids(parse_result.as_mut_slice(), &mut HashSet::new());
+    }
+    let mut link_references = mem::replace(&mut st.link_references, Vec::new());
+    for reference in link_references.iter_mut() {
+        reference.used = usage.used_labels.contains(&inline::normalize_label(reference.label.as_slice()));
+    }
+    return Document {
+        blocks: parse_result,
+        footnotes: footnotes,
+        unreferenced_footnotes: unreferenced_footnotes,
+        front_matter: front_matter,
+        link_references: link_references,
+        undefined_references: usage.undefined,
+    };
+}
+
+
+/// the front matter, if the option is enabled, and the line where the Markdown content starts
+fn find_front_matter(lines: &[String], source_map: &SourceMap,
+                     options: &ParseOptions) -> (Option<front_matter::FrontMatter>, uint) {
+    if !options.front_matter {
+        return (None, 0);
+    }
+    return match front_matter::parse_front_matter(lines, source_map) {
+        Some((front_matter, line_count)) => (Some(front_matter), line_count),
+        None => (None, 0),
+    }
+}
+
+
+/// parses the inline content of headers and paragraphs, which can only be done after all link
+/// reference definitions and footnote definitions are collected
+fn parse_inline_content(blocks: &mut Vec<Spanned<Block>>, refs: &inline::LinkReferenceMap,
+                        usage: &mut inline::ReferenceUsage, footnotes: &HashSet<String>,
+                        source_map: &SourceMap, options: &ParseOptions) {
+    for block in blocks.iter_mut() {
+        match block.node {
+            Block::Header(_, ref mut text, _) | Block::Paragraph(ref mut text) => {
+                let lines = mem::replace(text, Vec::new());
+                *text = inline::parse_inline(lines.as_slice(), refs, usage, footnotes, source_map, options);
+            },
+            Block::BlockQuote(ref mut blocks) => {
+                parse_inline_content(&mut **blocks, refs, usage, footnotes, source_map, options);
+            },
+            Block::List(_, ref mut items) => {
+                for item in items.iter_mut() {
+                    parse_inline_content(&mut *item.blocks, refs, usage, footnotes, source_map, options);
+                }
+            },
+            Block::Table(_, ref mut header, ref mut rows) => {
+                for cell in header.iter_mut().chain(rows.iter_mut().flat_map(|row| row.iter_mut())) {
+                    let lines = mem::replace(cell, Vec::new());
+                    *cell = inline::parse_inline(lines.as_slice(), refs, usage, footnotes, source_map, options);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+
+/// gives the headers without an explicit id an id from their text, and makes all ids unique by
+/// appending "-1", "-2" and so on
+fn assign_header_ids(blocks: &mut [Spanned<Block>], used_ids: &mut HashSet<String>) {
+    for block in blocks.iter_mut() {
+        match block.node {
+            Block::Header(_, ref text, ref mut id) => {
+                let base_id = match *id {
+                    Some(ref id) => id.clone(),
+                    None => toc::slugify(toc::slug_text(text).as_slice()),
+                };
+                let mut unique_id = base_id.clone();
+                let mut n = 1u;
+                while used_ids.contains(&unique_id) {
+                    unique_id = format!("{}-{}", base_id, n);
+                    n += 1;
+                }
+                used_ids.insert(unique_id.clone());
+                *id = Some(unique_id);
+            },
+            Block::BlockQuote(ref mut blocks) => {
+                assign_header_ids(blocks.as_mut_slice(), used_ids);
+            },
+            Block::List(_, ref mut items) => {
+                for item in items.iter_mut() {
+                    assign_header_ids(item.blocks.as_mut_slice(), used_ids);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
 
-    let st = &mut ParseState::new(md_lines.as_mut_slice());
+/// numbers the footnote references in the order of their first occurrence and returns the
+/// referenced footnotes in that order, and the unreferenced footnotes in the order of the source
+///
+/// References inside of footnotes count after those of the document, so footnotes that are
+/// only referenced by other footnotes come last. References inside of unreferenced footnotes
+/// don't count, and keep the number 0.
+fn number_footnotes(blocks: &mut Vec<Spanned<Block>>, definitions: Vec<Footnote>)
+                    -> (Vec<Footnote>, Vec<Footnote>) {
+    let mut definitions: Vec<Option<Footnote>> = definitions.into_iter().map(|f| Some(f)).collect();
+    let mut labels = Vec::new();
+    number_footnote_references(blocks.as_mut_slice(), &mut labels);
+
+    let mut footnotes = Vec::new();
+    let mut i = 0;
+    while i < labels.len() {
+        let index = definitions.iter().position(|definition| match *definition {
+            Some(ref f) => inline::normalize_label(f.label.as_slice()) == labels[i],
+            None => false,
+        });
+        if let Some(index) = index {
+            let mut footnote = definitions[index].take().unwrap();
+            footnote.number = i + 1;
+            number_footnote_references(footnote.blocks.as_mut_slice(), &mut labels);
+            footnotes.push(footnote);
+        }
+        i += 1;
+    }
+    let unreferenced_footnotes = definitions.into_iter().filter_map(|definition| definition).collect();
+    return (footnotes, unreferenced_footnotes);
+}
 
-    let parse_result = st.parse_document();
-    return format!("{}", parse_result);
+/// numbers the footnote references in blocks, adding the normalized labels that are referenced
+/// for the first time to `labels`
+fn number_footnote_references(blocks: &mut [Spanned<Block>], labels: &mut Vec<String>) {
+    for block in blocks.iter_mut() {
+        match block.node {
+            Block::Header(_, ref mut text, _) | Block::Paragraph(ref mut text) => {
+                number_inline_footnote_references(text, labels);
+            },
+            Block::BlockQuote(ref mut blocks) => {
+                number_footnote_references(blocks.as_mut_slice(), labels);
+            },
+            Block::List(_, ref mut items) => {
+                for item in items.iter_mut() {
+                    number_footnote_references(item.blocks.as_mut_slice(), labels);
+                }
+            },
+            Block::Table(_, ref mut header, ref mut rows) => {
+                for cell in header.iter_mut().chain(rows.iter_mut().flat_map(|row| row.iter_mut())) {
+                    number_inline_footnote_references(cell, labels);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+fn number_inline_footnote_references(text: &mut inline::InlineText, labels: &mut Vec<String>) {
+    for inline in text.iter_mut() {
+        match inline.node {
+            inline::Inline::FootnoteReference(ref label, ref mut number) => {
+                let normalized_label = inline::normalize_label(label.as_slice());
+                *number = match labels.iter().position(|l| *l == normalized_label) {
+                    Some(index) => index + 1,
+                    None => {
+                        labels.push(normalized_label);
+                        labels.len()
+                    }
+                };
+            },
+            inline::Inline::Link(ref mut content, _, _) | inline::Inline::Image(ref mut content, _, _) |
+                    inline::Inline::Emph(ref mut content) | inline::Inline::Strong(ref mut content) |
+                    inline::Inline::Strikethrough(ref mut content) => {
+                number_inline_footnote_references(content, labels);
+            },
+            _ => {}
+        }
+    }
 }
 
 
@@ -696,27 +1406,226 @@ fn druck(s: String) {
 
 
 
+#[cfg(test)]
+fn parses_to_header(teststring: &str, level: uint, text: &str) -> bool {
+    let document = parse_markdown(teststring, &Default::default());
+    if document.blocks.len() != 1 {
+        return false;
+    }
+    return match document.blocks[0].node {
+        Block::Header(header_level, ref inlines, _) => {
+            header_level == level && match inlines.as_slice() {
+                [Spanned { node: inline::Inline::Text(ref header_text), .. }] => header_text.as_slice() == text,
+                _ => false
+            }
+        },
+        _ => false
+    }
+}
+
 #[test]
 fn test_atx_headers() {
-    let text = preprocess_text("# eine Überschrift");
-    let s = ParseState::new(text.as_slice());
-    let block = parse_atx_header(s).unwrap().val0();
-    match block {
-        Block::Header(1, "eine Überschrift") => assert!(true),
-        _ => assert!(false)
+    assert!(parses_to_header("# eine Überschrift", 1, "eine Überschrift"));
+    assert!(parses_to_header("### eine Überschrift ###", 3, "eine Überschrift"));
+    assert!(!parses_to_header("####### keine Überschrift", 7, "keine Überschrift"));
+}
+
+#[test]
+fn test_setext_headers() {
+    assert!(parses_to_header("eine Überschrift\n===", 1, "eine Überschrift"));
+    assert!(parses_to_header("eine Überschrift\n---", 2, "eine Überschrift"));
+}
+
+#[test]
+fn test_positions() {
+    let document = parse_markdown("# foo\n\n> bar\n>\tbaz *qux*", &Default::default());
+    assert_eq!(document.blocks.len(), 2);
+    assert_eq!(document.blocks[0].span.start, Position { line: 1, column: 1, offset: 0 });
+    assert_eq!(document.blocks[0].span.end, Position { line: 1, column: 6, offset: 5 });
+
+    let blockquote = &document.blocks[1];
+    assert_eq!(blockquote.span.start, Position { line: 3, column: 1, offset: 7 });
+    assert_eq!(blockquote.span.end, Position { line: 4, column: 12, offset: 24 });
+    let paragraph = match blockquote.node {
+        Block::BlockQuote(ref blocks) => &blocks[0],
+        _ => panic!("not a block quote"),
+    };
+    assert_eq!(paragraph.span.start, Position { line: 3, column: 3, offset: 9 });
+    match paragraph.node {
+        Block::Paragraph(ref inlines) => {
+            let emph = inlines.last().unwrap();
+            assert_eq!(emph.span.start, Position { line: 4, column: 7, offset: 19 });
+            assert_eq!(emph.span.end, Position { line: 4, column: 12, offset: 24 });
+        },
+        _ => panic!("not a paragraph"),
+    }
+
+    // a line of only ">" after code in a block quote isn't part of the code block
+    let document = parse_markdown(">     code\n>\n> foo", &Default::default());
+    match document.blocks[0].node {
+        Block::BlockQuote(ref blocks) => {
+            assert_eq!(blocks[0].span.end, Position { line: 1, column: 11, offset: 10 });
+        },
+        _ => panic!("not a block quote"),
     }
 }
 
-#[cfg(test)]
-fn bla(teststring: &str, result: Block) -> bool {
-    let text = preprocess_text(teststring);
-    let s = ParseState::new(text.as_slice());
-    let block = parse_atx_header(s).unwrap().val0();
-    match block {
-        Block::Header(1, "eine Überschrift") => assert!(true),
-        _ => assert!(false)
+#[test]
+fn test_tab_width() {
+    let options = ParseOptions { tab_width: 0, ..Default::default() };
+    let document = parse_markdown("\tfoo\n\n    \tbar", &options);
+    assert_eq!(document.blocks.len(), 2);
+    match document.blocks[1].node {
+        Block::Code(None, ref code) => assert_eq!(code.as_slice(), "\tbar\n"),
+        _ => panic!("not an indented code block"),
+    }
+
+    let document = parse_markdown("\tfoo\tbar\n\n```make\nall:\n\tcc main.c\n```", &Default::default());
+    match (&document.blocks[0].node, &document.blocks[1].node) {
+        (&Block::Code(None, ref indented), &Block::Code(Some(_), ref fenced)) => {
+            assert_eq!(indented.as_slice(), "foo\tbar\n");
+            assert_eq!(fenced.as_slice(), "all:\n\tcc main.c\n");
+        },
+        _ => panic!("not two code blocks"),
+    }
+}
+
+#[test]
+fn test_tables() {
+    let options = ParseOptions::gfm();
+    let document = parse_markdown("text\n| a | b \\| c |\n|:--|--:|\n| d |\n\nafter", &options);
+    assert_eq!(document.blocks.len(), 3);
+    match document.blocks[1].node {
+        Block::Table(ref alignments, ref header, ref rows) => {
+            assert_eq!(*alignments, vec![Alignment::Left, Alignment::Right]);
+            assert_eq!(header.len(), 2);
+            match header[1].as_slice() {
+                [Spanned { node: inline::Inline::Text(ref text), .. }] => assert_eq!(text.as_slice(), "b | c"),
+                _ => panic!("unexpected header cell {:?}", header[1]),
+            }
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].len(), 2);
+            assert!(rows[0][1].is_empty());
+        },
+        _ => panic!("not a table"),
+    }
+
+    let document = parse_markdown("| a |\n| - |", &Default::default());
+    match document.blocks[0].node {
+        Block::Paragraph(_) => {},
+        _ => panic!("tables are an extension"),
+    }
+
+    // without a pipe in the header or delimiter row, a "---" line isn't a table
+    let document = parse_markdown("Foo\nBar\n---", &options);
+    assert_eq!(document.blocks.len(), 2);
+    match (&document.blocks[0].node, &document.blocks[1].node) {
+        (&Block::Paragraph(_), &Block::Rule) => {},
+        _ => panic!("unexpected blocks {:?}", document.blocks),
+    }
+}
+
+#[test]
+fn test_task_list_items() {
+    let document = parse_markdown("- [ ] foo\n- [x] bar\n- baz", &ParseOptions::gfm());
+    match document.blocks[0].node {
+        Block::List(_, ref items) => {
+            let checked: Vec<Option<bool>> = items.iter().map(|item| item.checked).collect();
+            assert_eq!(checked, vec![Some(false), Some(true), None]);
+        },
+        _ => panic!("not a list"),
+    }
+}
+
+#[test]
+fn test_extended_autolinks() {
+    let document = parse_markdown("Visit www.example.com/a_(b)). Or mail foo@bar.com!", &ParseOptions::gfm());
+    match document.blocks[0].node {
+        Block::Paragraph(ref inlines) => {
+            let nodes: Vec<&inline::Inline> = inlines.iter().map(|inline| &inline.node).collect();
+            match nodes.as_slice() {
+                [&inline::Inline::Text(_), &inline::Inline::URIAutolink(ref uri), &inline::Inline::Text(_),
+                 &inline::Inline::EmailAutolink(ref address), &inline::Inline::Text(_)] => {
+                    assert_eq!(uri.as_slice(), "www.example.com/a_(b)");
+                    assert_eq!(address.as_slice(), "foo@bar.com");
+                },
+                _ => panic!("unexpected inlines {:?}", nodes),
+            }
+        },
+        _ => panic!("not a paragraph"),
+    }
+
+    // the local part of an email address may follow quotes or other punctuation
+    let examples = [("\"help@example.com\"", "\"", "help@example.com"),
+                    ("email:help@example.com", "email:", "help@example.com"), ("'a@b.co'", "'", "a@b.co")];
+    for &(markdown, before, expected) in examples.iter() {
+        let document = parse_markdown(markdown, &ParseOptions::gfm());
+        match document.blocks[0].node {
+            Block::Paragraph(ref inlines) => {
+                match inlines[0].node {
+                    inline::Inline::Text(ref text) => assert_eq!(text.as_slice(), before),
+                    _ => panic!("not a text"),
+                }
+                match inlines[1].node {
+                    inline::Inline::EmailAutolink(ref address) => {
+                        assert_eq!(address.as_slice(), expected);
+                    },
+                    _ => panic!("not an email autolink"),
+                }
+            },
+            _ => panic!("not a paragraph"),
+        }
     }
 }
 
+#[test]
+fn test_footnotes() {
+    let document = parse_markdown("Text[^b] and[^a][^b].\n\n[^a]: First\n\n    more\n[^b]: Second\n[^c]: Unused",
+                                  &ParseOptions::gfm());
+    assert_eq!(document.blocks.len(), 1);
+    match document.blocks[0].node {
+        Block::Paragraph(ref inlines) => {
+            let nodes: Vec<&inline::Inline> = inlines.iter().map(|inline| &inline.node).collect();
+            match nodes.as_slice() {
+                [&inline::Inline::Text(_), &inline::Inline::FootnoteReference(_, 1), &inline::Inline::Text(_),
+                 &inline::Inline::FootnoteReference(_, 2), &inline::Inline::FootnoteReference(_, 1),
+                 &inline::Inline::Text(_)] => {},
+                _ => panic!("unexpected inlines {:?}", nodes),
+            }
+        },
+        _ => panic!("not a paragraph"),
+    }
 
+    let footnotes: Vec<(&str, uint, uint)> = document.footnotes.iter()
+        .map(|f| (f.label.as_slice(), f.number, f.blocks.len())).collect();
+    assert_eq!(footnotes, vec![("b", 1, 1), ("a", 2, 2)]);
+    let unreferenced_footnotes: Vec<&str> = document.unreferenced_footnotes.iter()
+        .map(|f| f.label.as_slice()).collect();
+    assert_eq!(unreferenced_footnotes, vec!["c"]);
+    assert_eq!(document.footnotes[1].span.start, Position { line: 3, column: 1, offset: 23 });
+}
 
+#[test]
+fn test_front_matter() {
+    let options = ParseOptions { front_matter: true, ..Default::default() };
+    let document = parse_markdown("---\ntitle: \"Hello: world\" # comment\ntags:\n  - a\n  - b\n---\n# Text", &options);
+    assert_eq!(document.blocks.len(), 1);
+    let front_matter = document.front_matter.unwrap();
+    assert_eq!(front_matter.format, front_matter::FrontMatterFormat::YAML);
+    assert_eq!(front_matter.raw.as_slice(), "title: \"Hello: world\" # comment\ntags:\n  - a\n  - b\n");
+    assert_eq!(front_matter.get("title"), Some("Hello: world"));
+    assert_eq!(front_matter.get("tags"), Some("- a\n- b"));
+    assert_eq!(front_matter.span.end, Position { line: 6, column: 4, offset: 57 });
+
+    let document = parse_markdown("+++\ntitle = 'Hello'\n[server]\nport = 8080\n+++\n", &options);
+    assert!(document.blocks.is_empty());
+    let front_matter = document.front_matter.unwrap();
+    assert_eq!(front_matter.format, front_matter::FrontMatterFormat::TOML);
+    assert_eq!(front_matter.values, vec![("title".to_string(), "Hello".to_string()),
+                                         ("server.port".to_string(), "8080".to_string())]);
+
+    // without a closing line, the first line is a horizontal rule
+    let document = parse_markdown("---\ntitle: x", &options);
+    assert!(document.front_matter.is_none());
+    assert_eq!(document.blocks.len(), 2);
+}
@@ -1,16 +1,187 @@
+use std::cmp::max;
 use std::iter::repeat;
 
-pub fn preprocess_text(s: &str) -> Vec<String> {
-    let mut input: String = s.to_string();
-    // delete all U+0000 characters
-    input = input.replace("\u{0000}", "\u{FFFC}");
-    // a string must end with \n in order to make lines_any() work
-    if !input.ends_with("\n") {
-        input.push('\n');
+use super::Position;
+
+
+/// the lines of the original input, to find the positions of preprocessed text in it
+#[derive(Show)]
+pub struct SourceMap {
+    lines: Vec<String>,
+    offsets: Vec<uint>,     // byte offset of each line in the input
+    tab_width: uint,
+}
+
+impl SourceMap {
+    /// the position of the byte at `index` of a preprocessed line
+    pub fn position(&self, line: uint, index: uint) -> Position {
+        if line >= self.lines.len() {
+            return self.end_position();
+        }
+        let original_line = self.lines[line].as_slice();
+        let original_index = original_index(original_line, index, self.tab_width);
+        return Position {
+            line: line + 1,
+            column: original_line.slice_to(original_index).chars().count() + 1,
+            offset: self.offsets[line] + original_index,
+        };
     }
+
+    /// the position of the byte `from_end` bytes before the end of a preprocessed line
+    ///
+    /// Stripping container prefixes only changes the beginning of a line, so the distance to the
+    /// end of the line is the same as in the line before stripping.
+    pub fn position_from_end(&self, line: uint, from_end: uint) -> Position {
+        if line >= self.lines.len() {
+            return self.end_position();
+        }
+        let length = expanded_index(self.lines[line].as_slice(), self.lines[line].len(), self.tab_width);
+        let index = if from_end > length { 0 } else { length - from_end };
+        return self.position(line, index);
+    }
+
+    /// the position `distance` bytes of the preprocessed line before `end`
+    ///
+    /// Counting from the end avoids ambiguities inside of expanded tabs, as an element always
+    /// ends after a character of the original line.
+    pub fn position_before(&self, end: Position, distance: uint) -> Position {
+        if end.line == 0 || end.line > self.lines.len() {
+            return end;
+        }
+        let line = end.line - 1;
+        let index = expanded_index(self.lines[line].as_slice(), end.offset - self.offsets[line], self.tab_width);
+        return self.position(line, if distance > index { 0 } else { index - distance });
+    }
+
+    /// the last `from_end` bytes of a preprocessed line with the tabs of the original line, like
+    /// the content of code blocks
+    ///
+    /// A tab that is only partly included stays expanded to the spaces of the included part.
+    pub fn original_text_from_end(&self, line: uint, from_end: uint) -> String {
+        if line >= self.lines.len() {
+            return "".to_string();
+        }
+        let original_line = self.lines[line].as_slice();
+        let length = expanded_index(original_line, original_line.len(), self.tab_width);
+        let index = if from_end > length { 0 } else { length - from_end };
+
+        let mut result = String::new();
+        let mut rest_start = original_line.len();
+        for &(original, expanded) in index_pairs(original_line, self.tab_width).iter() {
+            if expanded >= index {
+                // the index may be inside of the expanded tab before
+                let spaces: String = repeat(' ').take(expanded - index).collect();
+                result.push_str(spaces.as_slice());
+                rest_start = original;
+                break;
+            }
+        }
+        result.push_str(original_line.slice_from(rest_start));
+        return result.replace("\u{0000}", "\u{FFFC}");
+    }
+
+    /// a line of the input as it was before preprocessing
+    pub fn line(&self, line: uint) -> &str {
+        return self.lines[line].as_slice();
+    }
+
+    /// whether a line of the input consists of whitespace only
+    pub fn is_blank(&self, line: uint) -> bool {
+        return line < self.lines.len() && self.lines[line].as_slice().trim().is_empty();
+    }
+
+    fn end_position(&self) -> Position {
+        return match self.lines.last() {
+            Some(last_line) => Position {
+                line: self.lines.len(),
+                column: last_line.as_slice().chars().count() + 1,
+                offset: self.offsets[self.lines.len() - 1] + last_line.len(),
+            },
+            None => Position { line: 1, column: 1, offset: 0 },
+        }
+    }
+}
+
+
+/// splits the input into preprocessed lines, and also returns the original lines
+///
+/// A `tab_width` of 0 is treated as 1.
+pub fn preprocess_text(s: &str, tab_width: uint) -> (Vec<String>, SourceMap) {
+    let tab_width = max(1, tab_width);
     let mut result: Vec<String> = Vec::new();
-    for line in input.lines_any() {
-        result.push(preprocess_line(line));
+    let mut source_map = SourceMap { lines: Vec::new(), offsets: Vec::new(), tab_width: tab_width };
+    let mut line_start = 0u;
+    while line_start < s.len() || result.is_empty() {
+        let line_end = match s.slice_from(line_start).find('\n') {
+            Some(i) => line_start + i,
+            None => s.len(),
+        };
+        let mut line = s.slice(line_start, line_end);
+        if line.ends_with("\r") {
+            line = line.slice_to(line.len() - 1);
+        }
+
+        // U+0000 characters are replaced
+        let replaced_line = line.replace("\u{0000}", "\u{FFFC}");
+        result.push(preprocess_line(replaced_line.as_slice(), tab_width));
+        source_map.lines.push(line.to_string());
+        source_map.offsets.push(line_start);
+
+        line_start = line_end + 1;
+    }
+    return (result, source_map);
+}
+
+
+/// pairs of byte indices in an original line and the preprocessed line, one for each character
+/// of the original line and one for its end
+fn index_pairs(line: &str, tab_width: uint) -> Vec<(uint, uint)> {
+    let mut pairs: Vec<(uint, uint)> = Vec::new();
+    let mut original = 0u;
+    let mut expanded = 0u;
+    let mut col = 0u;
+    let parts: Vec<&str> = line.split('\u{0009}').collect();
+    let part_count: uint = parts.len();
+    for (part_nr, part) in parts.iter().enumerate() {
+        for (i, ch) in part.char_indices() {
+            pairs.push((original + i, expanded));
+            expanded += if ch == '\u{0000}' { '\u{FFFC}'.len_utf8() } else { ch.len_utf8() };
+        }
+        original += part.len();
+        // the tab after the part, expanded the same way as in preprocess_line
+        if part_nr < part_count - 1 {
+            pairs.push((original, expanded));
+            col += part.graphemes(true).count();
+            let additional_spaces = tab_width - (col % tab_width);
+            col += additional_spaces;
+            expanded += additional_spaces;
+            original += 1;
+        }
+    }
+    pairs.push((original, expanded));
+    return pairs;
+}
+
+/// the byte index in the original line of the character at `index` in the preprocessed line
+fn original_index(line: &str, index: uint, tab_width: uint) -> uint {
+    let mut result = 0;
+    for &(original, expanded) in index_pairs(line, tab_width).iter() {
+        if expanded > index {
+            break;
+        }
+        result = original;
+    }
+    return result;
+}
+
+/// the byte index in the preprocessed line of the character at `index` in the original line
+fn expanded_index(line: &str, index: uint, tab_width: uint) -> uint {
+    let mut result = 0;
+    for &(original, expanded) in index_pairs(line, tab_width).iter() {
+        if original > index {
+            break;
+        }
+        result = expanded;
     }
     return result;
 }
@@ -18,8 +189,8 @@ pub fn preprocess_text(s: &str) -> Vec<String> {
 
 /// expands all tabs to spaces
 ///
-/// with tabstop of 4 characters
-fn preprocess_line(s: &str) -> String {
+/// with tab stops every `tab_width` characters
+fn preprocess_line(s: &str, tab_width: uint) -> String {
     if !s.contains_char('\u{0009}') {
         return s.to_string();
     }
@@ -33,7 +204,7 @@ fn preprocess_line(s: &str) -> String {
         if part_nr < part_count - 1 {
             let part_length = part.graphemes(true).count();
             col += part_length;
-            let additional_spaces = 4 - (col % 4);
+            let additional_spaces = tab_width - (col % tab_width);
             let spaces: String = repeat(' ').take(additional_spaces).collect();
             result.push_str(spaces.as_slice());
             col += additional_spaces;
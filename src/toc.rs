@@ -0,0 +1,137 @@
+use parser::{Block, Document, Spanned};
+use parser::inline::{Inline, InlineText, plain_text};
+
+
+/// an entry of a table of contents, with the entries of the headers below it
+#[derive(Show,Clone)]
+pub struct TocEntry {
+    /// the level of the header (1 to 6)
+    pub level: uint,
+    /// the text of the header without markup
+    pub text: String,
+    /// the id of the header, which is only given with the header ids option
+    pub id: Option<String>,
+    /// the entries of the following headers with a higher level, up to the next header with
+    /// the same or a lower level
+    pub children: Vec<TocEntry>,
+}
+
+
+/// the nested table of contents of all headers of a document
+pub fn table_of_contents(document: &Document) -> Vec<TocEntry> {
+    let mut entries = Vec::new();
+    push_entries(&mut entries, document.blocks.as_slice());
+    return entries;
+}
+
+fn push_entries(entries: &mut Vec<TocEntry>, blocks: &[Spanned<Block>]) {
+    for block in blocks.iter() {
+        match block.node {
+            Block::Header(level, ref text, ref id) => {
+                let entry = TocEntry { level: level, text: plain_text(text), id: id.clone(), children: Vec::new() };
+                insert_entry(entries, entry);
+            },
+            Block::BlockQuote(ref blocks) => {
+                push_entries(entries, blocks.as_slice());
+            },
+            Block::List(_, ref items) => {
+                for item in items.iter() {
+                    push_entries(entries, item.blocks.as_slice());
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/// adds an entry below the last entry if that has a lower level, otherwise after it
+fn insert_entry(entries: &mut Vec<TocEntry>, entry: TocEntry) {
+    let nested = match entries.last() {
+        Some(last) => last.level < entry.level,
+        None => false,
+    };
+    if nested {
+        insert_entry(&mut entries.last_mut().unwrap().children, entry);
+    } else {
+        entries.push(entry);
+    }
+}
+
+
+/// whether a block is a paragraph consisting of "[TOC]", where a table of contents is inserted
+pub fn is_toc_marker(block: &Block) -> bool {
+    return match *block {
+        Block::Paragraph(ref text) => match text.as_slice() {
+            [Spanned { node: Inline::Text(ref s), .. }] => s.as_slice() == "[TOC]",
+            _ => false
+        },
+        _ => false
+    }
+}
+
+
+/// the GitHub style id of a header text: lowercase, without punctuation and with spaces replaced
+/// by "-". An empty id becomes "section"
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for ch in text.trim().chars() {
+        if ch.is_alphanumeric() || ch == '-' || ch == '_' {
+            slug.push(ch.to_lowercase());
+        } else if ch == ' ' {
+            slug.push('-');
+        }
+    }
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+    return slug;
+}
+
+/// the text of a header that its id is made from, which is the plain text without inline HTML
+/// tags
+pub fn slug_text(text: &InlineText) -> String {
+    let mut result = String::new();
+    for inline in text.iter() {
+        match inline.node {
+            Inline::HTMLTag(_) => {},
+            Inline::Link(ref content, _, _) | Inline::Image(ref content, _, _) |
+                    Inline::Emph(ref content) | Inline::Strong(ref content) |
+                    Inline::Strikethrough(ref content) => {
+                result.push_str(slug_text(content).as_slice());
+            },
+            Inline::URIAutolink(ref s) | Inline::EmailAutolink(ref s) | Inline::CodeSpan(ref s) |
+                    Inline::Text(ref s) => {
+                result.push_str(s.as_slice());
+            },
+            Inline::FootnoteReference(_, number) => {
+                result.push_str(number.to_string().as_slice());
+            },
+            Inline::HardBreak | Inline::SoftBreak => {
+                result.push(' ');
+            },
+        }
+    }
+    return result;
+}
+
+
+#[test]
+fn test_table_of_contents() {
+    use std::default::Default;
+    use parser::{ParseOptions, parse_markdown};
+
+    let options = ParseOptions { header_ids: true, ..Default::default() };
+    let document = parse_markdown("# Intro\n\n## *Getting* started!\n\n### Details {#more}\n\nText\n\n## Getting started\n\n# Intro", &options);
+    let toc = table_of_contents(&document);
+    let summary: Vec<(uint, &str, &str, uint)> = toc.iter()
+        .map(|e| (e.level, e.text.as_slice(), e.id.as_ref().unwrap().as_slice(), e.children.len())).collect();
+    assert_eq!(summary, vec![(1, "Intro", "intro", 2), (1, "Intro", "intro-1", 0)]);
+    let children: Vec<&str> = toc[0].children.iter().map(|e| e.id.as_ref().unwrap().as_slice()).collect();
+    assert_eq!(children, vec!["getting-started", "getting-started-1"]);
+    assert_eq!(toc[0].children[0].children[0].id, Some("more".to_string()));
+    assert_eq!(toc[0].children[0].children[0].text.as_slice(), "Details");
+    assert_eq!(slugify("Hello, World -- ünïcode_ok?"), "hello-world----ünïcode_ok".to_string());
+
+    let document = parse_markdown("## <code>x</code> API", &options);
+    assert_eq!(table_of_contents(&document)[0].id, Some("x-api".to_string()));
+}
@@ -0,0 +1,132 @@
+//! Runs the examples of the CommonMark spec in `tests/spec.txt`, the unchanged `spec.txt` of
+//! version 0.31.2, and reports the results per section of the spec.
+//!
+//! The examples in `KNOWN_FAILURES` are expected to fail, so the test fails on regressions while
+//! the parser is incomplete. Fixed examples must be removed from the list. When the list doesn't
+//! match, the test prints the failing examples of the run as the new list.
+
+extern crate rustmark;
+
+use std::io::fs::File;
+
+static EXAMPLE_START: &'static str = "```````````````````````````````` example";
+static EXAMPLE_END: &'static str = "````````````````````````````````";
+
+/// the numbers of the examples in the spec that fail
+static KNOWN_FAILURES: &'static [uint] = &[];
+
+struct Example {
+    number: uint,
+    section: String,
+    markdown: String,
+    html: String,
+}
+
+enum ReadState {
+    Text,
+    Markdown,
+    HTML,
+}
+
+/// collects the examples of a file in spec.txt format
+fn read_examples(spec: &str) -> Vec<Example> {
+    let mut examples: Vec<Example> = Vec::new();
+    let mut section = "".to_string();
+    let mut markdown = "".to_string();
+    let mut html = "".to_string();
+    let mut state = ReadState::Text;
+
+    for line in spec.lines_any() {
+        match state {
+            ReadState::Text => {
+                if line.starts_with(EXAMPLE_START) {
+                    state = ReadState::Markdown;
+                } else if line.starts_with("#") {
+                    section = line.trim_left_matches('#').trim().to_string();
+                }
+            },
+            ReadState::Markdown => {
+                if line == "." {
+                    state = ReadState::HTML;
+                } else {
+                    markdown.push_str(line.replace("→", "\t").as_slice());
+                    markdown.push('\n');
+                }
+            },
+            ReadState::HTML => {
+                if line == EXAMPLE_END {
+                    examples.push(Example {
+                        number: examples.len() + 1,
+                        section: section.clone(),
+                        markdown: markdown,
+                        html: html
+                    });
+                    markdown = "".to_string();
+                    html = "".to_string();
+                    state = ReadState::Text;
+                } else {
+                    html.push_str(line.replace("→", "\t").as_slice());
+                    html.push('\n');
+                }
+            }
+        }
+    }
+    return examples;
+}
+
+#[test]
+fn spec_examples() {
+    let mut spec_file = File::open(&Path::new("tests/spec.txt")).ok().expect("tests/spec.txt not found");
+    let spec = spec_file.read_to_string().ok().expect("can't read tests/spec.txt");
+    let examples = read_examples(spec.as_slice());
+
+    // (section, passed, total) in the order of the spec
+    let mut results: Vec<(String, uint, uint)> = Vec::new();
+    let mut failed: Vec<uint> = Vec::new();
+    let mut regressions: Vec<uint> = Vec::new();
+    let mut fixed: Vec<uint> = Vec::new();
+
+    for example in examples.iter() {
+        let document = rustmark::parse(example.markdown.as_slice());
+        let html = rustmark::render_html(&document);
+        let passed = html == example.html;
+        let known_failure = KNOWN_FAILURES.contains(&example.number);
+        if passed && known_failure {
+            fixed.push(example.number);
+        } else if !passed && !known_failure {
+            regressions.push(example.number);
+        }
+        if !passed {
+            failed.push(example.number);
+            println!("Example {} ({}) failed\n--- markdown\n{}--- expected\n{}--- got\n{}",
+                     example.number, example.section, example.markdown, example.html, html);
+        }
+
+        let is_new_section = match results.last() {
+            Some(&(ref section, _, _)) => *section != example.section,
+            None => true,
+        };
+        if is_new_section {
+            results.push((example.section.clone(), 0, 0));
+        }
+        let last = results.len() - 1;
+        let (_, ref mut section_passed, ref mut section_total) = results[last];
+        *section_total += 1;
+        if passed {
+            *section_passed += 1;
+        }
+    }
+
+    for &(ref section, passed, total) in results.iter() {
+        println!("{:<45} {:>4}/{:<4}", section, passed, total);
+    }
+    println!("{} of {} examples passed", examples.len() - failed.len(), examples.len());
+
+    if !fixed.is_empty() {
+        println!("Examples {} pass now and can be removed from KNOWN_FAILURES", fixed);
+    }
+    if !fixed.is_empty() || !regressions.is_empty() {
+        println!("KNOWN_FAILURES of this run: {}", failed);
+    }
+    assert!(regressions.is_empty(), "Examples {} failed and are not in KNOWN_FAILURES", regressions);
+}